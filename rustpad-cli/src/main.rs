@@ -0,0 +1,244 @@
+//! Command-line tool for editing Rustpad documents as local files.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use rustpad_client::{Client, Event, OpSeq};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::{fs, time};
+
+const USAGE: &str = "\
+Usage:
+  rustpad get <id>          Print the text of a document
+  rustpad put <id> < FILE   Replace the text of a document with standard input
+  rustpad sync <id> <FILE>  Keep a local file and a document in sync
+
+The server is read from the RUSTPAD_SERVER environment variable, and defaults
+to http://localhost:3030.";
+
+/// Interval between checks of a synced file for local changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Delay before reconnecting to a document after the connection is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["get", id] => get(id).await,
+        ["put", id] => put(id).await,
+        ["sync", id, path] => sync(id, Path::new(path)).await,
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("error: {e:#}");
+        process::exit(1);
+    }
+}
+
+/// Returns the WebSocket URL of a document on the configured server.
+fn socket_url(id: &str) -> String {
+    let server =
+        std::env::var("RUSTPAD_SERVER").unwrap_or_else(|_| String::from("http://localhost:3030"));
+    let server = server.trim_end_matches('/');
+    let server = match server.split_once("://") {
+        Some(("https", rest)) => format!("wss://{rest}"),
+        Some(("http", rest)) => format!("ws://{rest}"),
+        _ => server.to_owned(),
+    };
+    format!("{server}/api/socket/{id}")
+}
+
+/// Print the text of a document to standard output.
+async fn get(id: &str) -> Result<()> {
+    let client = Client::connect(&socket_url(id)).await?;
+    io::stdout().write_all(client.text().as_bytes()).await?;
+    io::stdout().flush().await?;
+    client.close().await
+}
+
+/// Replace the text of a document with standard input.
+async fn put(id: &str) -> Result<()> {
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .await
+        .context("failed to read standard input")?;
+    let mut client = Client::connect(&socket_url(id)).await?;
+    if client.text() != text {
+        client.edit(OpSeq::from_diff(client.text(), &text)).await?;
+        client.sync().await?;
+    }
+    client.close().await
+}
+
+/// Keep a local file and a document in sync until interrupted, reconnecting
+/// when the connection is lost. A file that differs from the document is only
+/// overwritten when starting after asking, unless the document is empty, in
+/// which case the file is uploaded instead.
+async fn sync(id: &str, path: &Path) -> Result<()> {
+    let mut client = Client::connect(&socket_url(id)).await?;
+    // The text last written to or read from the file, which is the same as
+    // the text of the client once all events have been handled.
+    let mut last = client.text().to_owned();
+    match read(path).await? {
+        Some(text) if text == last => {}
+        Some(text) if last.is_empty() => {
+            client.edit(OpSeq::from_diff(&last, &text)).await?;
+            last = text;
+        }
+        Some(text) if !text.is_empty() => {
+            let question = format!(
+                "{} differs from document {}, overwrite it?",
+                path.display(),
+                id
+            );
+            if !confirm(&question).await? {
+                bail!("{} differs from document {}", path.display(), id);
+            }
+            write(path, &last).await?;
+        }
+        _ => write(path, &last).await?,
+    }
+    info!("syncing {} with document {}", path.display(), id);
+
+    loop {
+        let error = match follow(&mut client, path, &mut last).await {
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+        let Disconnected(error) = error.downcast()?;
+        warn!("connection lost: {error:#}");
+        client = loop {
+            time::sleep(RECONNECT_DELAY).await;
+            match Client::connect(&socket_url(id)).await {
+                Ok(client) => break client,
+                Err(e) => warn!("failed to reconnect: {e:#}"),
+            }
+        };
+        info!("reconnected to document {}", id);
+    }
+}
+
+/// Error from the connection to the server, after which [`sync`] reconnects.
+#[derive(Debug)]
+struct Disconnected(anyhow::Error);
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// Sync a file with a document until the connection fails with
+/// [`Disconnected`], or reading or writing the file fails.
+///
+/// The file is first merged with any edits made to the document since it
+/// contained `last`, such as while reconnecting. Local edits that the server
+/// had not received when the connection was lost are only kept if they are
+/// still in the file.
+async fn follow(client: &mut Client, path: &Path, last: &mut String) -> Result<Infallible> {
+    let remote = OpSeq::from_diff(last, client.text());
+    merge(client, path, last, &remote).await?;
+
+    // Remote edits applied to the client since `last`, which are written to
+    // the file once the client has returned all of their events.
+    let mut remote: Option<OpSeq> = None;
+    let mut poll = time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            event = client.recv() => {
+                let Event::Edit { operation, .. } = event.map_err(Disconnected)? else {
+                    continue;
+                };
+                let operation = match remote.take() {
+                    Some(remote) => remote
+                        .compose(&operation)
+                        .context("failed to compose remote edits")?,
+                    None => operation,
+                };
+                // Events are returned after the whole message containing them
+                // was applied, so later edits may already be in the text.
+                if operation.apply(last).as_deref() != Some(client.text()) {
+                    remote = Some(operation);
+                    continue;
+                }
+                merge(client, path, last, &operation).await?;
+            }
+            _ = poll.tick() => {
+                if client.text() != last {
+                    continue; // Remote edits are pending, handled above.
+                }
+                // Editors may briefly remove the file while saving it.
+                if let Some(text) = read(path).await? {
+                    if text != *last {
+                        let operation = OpSeq::from_diff(last, &text);
+                        client.edit(operation).await.map_err(Disconnected)?;
+                        *last = text;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Write the text of the client to a file that last contained `last`, after
+/// `remote` was applied to the client. Local changes that were saved since
+/// then are kept, by transforming them against the remote edits.
+async fn merge(client: &mut Client, path: &Path, last: &mut String, remote: &OpSeq) -> Result<()> {
+    let text = read(path).await?;
+    if let Some(text) = &text {
+        if text != last {
+            let (local, _) = OpSeq::from_diff(last, text)
+                .transform_raw(remote)
+                .context("failed to transform local changes")?;
+            client.edit(local).await.map_err(Disconnected)?;
+        }
+    }
+    *last = client.text().to_owned();
+    if text.as_deref() != Some(last.as_str()) {
+        write(path, last).await?;
+    }
+    Ok(())
+}
+
+/// Ask a question on the terminal, returning if it was answered with yes.
+async fn confirm(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    BufReader::new(io::stdin())
+        .read_line(&mut answer)
+        .await
+        .context("failed to read standard input")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Read a file, returning `None` if it does not exist.
+async fn read(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path).await {
+        Ok(text) => Ok(Some(text)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+async fn write(path: &Path, text: &str) -> Result<()> {
+    fs::write(path, text)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+}
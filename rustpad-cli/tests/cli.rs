@@ -0,0 +1,197 @@
+//! Tests for the command-line tool against a running server.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Result;
+use rustpad_client::{Client, Event, OpSeq};
+use rustpad_server::{server, ServerConfig};
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::{fs, time};
+
+/// Serve a fresh server on an ephemeral port.
+fn spawn_server() -> SocketAddr {
+    let filter = server(ServerConfig::default());
+    let (addr, serve) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+    addr
+}
+
+/// Forward connections to a server until `drop` is changed, which closes all
+/// forwarded connections while still accepting new ones.
+async fn spawn_proxy(server: SocketAddr, drop: watch::Receiver<()>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut drop = drop.clone();
+            drop.mark_unchanged();
+            tokio::spawn(async move {
+                let mut upstream = TcpStream::connect(server).await?;
+                tokio::select! {
+                    result = tokio::io::copy_bidirectional(&mut socket, &mut upstream) => {
+                        result?;
+                    }
+                    _ = drop.changed() => {}
+                }
+                anyhow::Ok(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+fn rustpad(addr: SocketAddr) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rustpad"));
+    command
+        .env("RUSTPAD_SERVER", format!("http://{addr}"))
+        .kill_on_drop(true);
+    command
+}
+
+/// Wait until a file has the expected contents.
+async fn expect_file(path: &Path, text: &str) {
+    for _ in 0..100 {
+        if fs::read_to_string(path).await.ok().as_deref() == Some(text) {
+            return;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} never contained {:?}", path.display(), text);
+}
+
+#[tokio::test]
+async fn test_get_put() -> Result<()> {
+    let addr = spawn_server();
+
+    let mut put = rustpad(addr)
+        .args(["put", "foobar"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = put.stdin.take().unwrap();
+    stdin.write_all("hello\nw\u{f6}rld\n".as_bytes()).await?;
+    drop(stdin);
+    assert!(put.wait().await?.success());
+
+    let get = rustpad(addr).args(["get", "foobar"]).output().await?;
+    assert!(get.status.success());
+    assert_eq!(String::from_utf8(get.stdout)?, "hello\nw\u{f6}rld\n");
+
+    let usage = rustpad(addr).arg("get").output().await?;
+    assert_eq!(usage.status.code(), Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync() -> Result<()> {
+    let addr = spawn_server();
+    let dir = TempDir::new()?;
+    let path = dir.path().join("notes.txt");
+
+    let mut client = Client::connect(&format!("ws://{addr}/api/socket/foobar")).await?;
+    let mut operation = OpSeq::new();
+    operation.insert("hello");
+    client.edit(operation).await?;
+    client.sync().await?;
+
+    let _sync = rustpad(addr).arg("sync").arg("foobar").arg(&path).spawn()?;
+    expect_file(&path, "hello").await;
+
+    let mut operation = OpSeq::new();
+    operation.retain(5);
+    operation.insert(" world");
+    client.edit(operation).await?;
+    expect_file(&path, "hello world").await;
+
+    fs::write(&path, "hello, world!").await?;
+    while client.text() != "hello, world!" {
+        let event = time::timeout(Duration::from_secs(5), client.recv()).await??;
+        assert!(matches!(event, Event::Edit { .. }));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_existing_file() -> Result<()> {
+    let addr = spawn_server();
+    let dir = TempDir::new()?;
+    let path = dir.path().join("notes.txt");
+
+    // Files are uploaded to empty documents.
+    fs::write(&path, "local").await?;
+    let _sync = rustpad(addr).arg("sync").arg("empty").arg(&path).spawn()?;
+    let mut client = Client::connect(&format!("ws://{addr}/api/socket/empty")).await?;
+    while client.text() != "local" {
+        client = Client::connect(&format!("ws://{addr}/api/socket/empty")).await?;
+        time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Other files are not overwritten without asking.
+    let mut client = Client::connect(&format!("ws://{addr}/api/socket/foobar")).await?;
+    let mut operation = OpSeq::new();
+    operation.insert("remote");
+    client.edit(operation).await?;
+    client.sync().await?;
+    let sync = rustpad(addr)
+        .arg("sync")
+        .arg("foobar")
+        .arg(&path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!sync.status.success());
+    assert_eq!(fs::read_to_string(&path).await?, "local");
+
+    let mut sync = rustpad(addr)
+        .arg("sync")
+        .arg("foobar")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = sync.stdin.take().unwrap();
+    stdin.write_all(b"y\n").await?;
+    expect_file(&path, "remote").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_reconnect() -> Result<()> {
+    let (drop, dropped) = watch::channel(());
+    let addr = spawn_proxy(spawn_server(), dropped).await?;
+    let dir = TempDir::new()?;
+    let path = dir.path().join("notes.txt");
+
+    let _sync = rustpad(addr).arg("sync").arg("foobar").arg(&path).spawn()?;
+    expect_file(&path, "").await;
+    let mut client = Client::connect(&format!("ws://{addr}/api/socket/foobar")).await?;
+    fs::write(&path, "hello").await?;
+    while client.text() != "hello" {
+        client.recv().await?;
+    }
+
+    // Edits on both sides while disconnected are merged after reconnecting.
+    drop.send(())?;
+    let mut client = Client::connect(&format!("ws://{addr}/api/socket/foobar")).await?;
+    let mut operation = OpSeq::new();
+    operation.insert("> ");
+    operation.retain(5);
+    client.edit(operation).await?;
+    fs::write(&path, "hello world").await?;
+    expect_file(&path, "> hello world").await;
+    while client.text() != "> hello world" {
+        let event = time::timeout(Duration::from_secs(5), client.recv()).await??;
+        assert!(matches!(event, Event::Edit { .. }));
+    }
+
+    Ok(())
+}
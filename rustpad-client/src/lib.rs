@@ -0,0 +1,269 @@
+//! Native client for Rustpad, for scripting documents from Rust.
+//!
+//! A [`Client`] keeps a local copy of a document in sync with the server using
+//! the same [`ClientState`] as the browser editor. Local edits are applied
+//! immediately, and remote edits are transformed against the local edits that
+//! the server has not acknowledged yet before being applied.
+
+#![warn(missing_docs)]
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Context, Result};
+use futures::prelude::*;
+use log::{info, warn};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::protocol::{ClientMsg, ServerMsg, UserOperation};
+
+mod protocol;
+
+pub use protocol::{CursorData, UserInfo};
+pub use rustpad_wasm::{ClientState, OpSeq};
+
+/// Version of the WebSocket protocol spoken by this client.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// An update to the document or its users, received from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Another user edited the text. The operation has been transformed
+    /// against local edits and already applied to [`Client::text`].
+    Edit {
+        /// ID of the user who made the edit.
+        id: u64,
+        /// The edit, relative to the local text before it was applied.
+        operation: OpSeq,
+    },
+    /// The language of the document was changed.
+    Language(String),
+    /// A user's information was changed, or `None` if they disconnected.
+    UserInfo {
+        /// ID of the user.
+        id: u64,
+        /// The user's new information.
+        info: Option<UserInfo>,
+    },
+    /// A user moved their cursor or selection.
+    UserCursor {
+        /// ID of the user.
+        id: u64,
+        /// The user's new cursor positions, relative to the server's text.
+        data: CursorData,
+    },
+    /// The server rejected a message from this client.
+    Error(String),
+}
+
+/// A connection to a single document.
+pub struct Client {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    id: u64,
+    text: String,
+    state: ClientState,
+    /// Events received but not yet returned from [`Client::recv`].
+    events: VecDeque<Event>,
+    /// Messages waiting to be written to the socket.
+    outgoing: VecDeque<Message>,
+}
+
+impl Client {
+    /// Connect to a document at its WebSocket URL, such as
+    /// `ws://localhost:3030/api/socket/<id>`, and wait for its current text.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}version={PROTOCOL_VERSION}&snapshot=true");
+        let (socket, _) = connect_async(url).await.context("failed to connect")?;
+        let mut client = Self {
+            socket,
+            id: 0,
+            text: String::new(),
+            state: ClientState::new(),
+            events: VecDeque::new(),
+            outgoing: VecDeque::new(),
+        };
+        loop {
+            match client.next_message().await? {
+                ServerMsg::Snapshot { revision, text } => {
+                    client.state = ClientState::from_snapshot(revision);
+                    client.text = text;
+                    return Ok(client);
+                }
+                // The server rejects clients before sending the document, such
+                // as when it speaks a different protocol version.
+                ServerMsg::Error(error) => bail!("server rejected connection: {error}"),
+                msg => client.handle(msg)?,
+            }
+        }
+    }
+
+    /// Returns the ID assigned to this client by the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the local text, including edits not yet acknowledged.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the number of operations received from the server.
+    pub fn revision(&self) -> usize {
+        self.state.revision()
+    }
+
+    /// Returns if the server has acknowledged all local edits.
+    pub fn synced(&self) -> bool {
+        self.state.outstanding().is_none()
+    }
+
+    /// Apply an edit to the local text and send it to the server.
+    pub async fn edit(&mut self, operation: OpSeq) -> Result<()> {
+        self.text = operation
+            .apply(&self.text)
+            .context("edit does not apply to the current text")?;
+        if let Some(operation) = self.state.apply_local(&operation) {
+            self.queue_edit(operation);
+        }
+        self.flush().await
+    }
+
+    /// Set the language of the document.
+    pub async fn set_language(&mut self, language: &str) -> Result<()> {
+        self.send(&ClientMsg::SetLanguage(language.into())).await
+    }
+
+    /// Set the information shown to other users.
+    pub async fn set_info(&mut self, info: UserInfo) -> Result<()> {
+        self.send(&ClientMsg::ClientInfo(info)).await
+    }
+
+    /// Set the cursor and selection positions shown to other users.
+    pub async fn set_cursor(&mut self, data: CursorData) -> Result<()> {
+        self.send(&ClientMsg::CursorData(data)).await
+    }
+
+    /// Wait for the next event from the server, keeping the text in sync.
+    ///
+    /// This is cancel safe, so it can be used in `tokio::select!` without
+    /// losing events or edits.
+    pub async fn recv(&mut self) -> Result<Event> {
+        loop {
+            self.flush().await?;
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let msg = self.next_message().await?;
+            self.handle(msg)?;
+        }
+    }
+
+    /// Wait until the server has acknowledged all local edits. Events received
+    /// in the meantime are returned by later calls to [`Client::recv`].
+    pub async fn sync(&mut self) -> Result<()> {
+        loop {
+            self.flush().await?;
+            if self.synced() {
+                return Ok(());
+            }
+            let msg = self.next_message().await?;
+            self.handle(msg)?;
+        }
+    }
+
+    /// Close the connection.
+    pub async fn close(mut self) -> Result<()> {
+        self.socket.close(None).await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ClientMsg) -> Result<()> {
+        self.queue(msg);
+        self.flush().await
+    }
+
+    fn queue(&mut self, msg: &ClientMsg) {
+        let msg = serde_json::to_string(msg).expect("failed serialize");
+        self.outgoing.push_back(Message::text(msg));
+    }
+
+    fn queue_edit(&mut self, operation: OpSeq) {
+        let revision = self.state.revision();
+        self.queue(&ClientMsg::Edit {
+            revision,
+            operation,
+        });
+    }
+
+    /// Write all queued messages to the socket. Messages are only removed from
+    /// the queue once written, so that this can be cancelled at any point.
+    async fn flush(&mut self) -> Result<()> {
+        while let Some(msg) = self.outgoing.front() {
+            self.socket.feed(msg.clone()).await?;
+            self.outgoing.pop_front();
+        }
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    /// Receive the next message, skipping control frames.
+    async fn next_message(&mut self) -> Result<ServerMsg> {
+        loop {
+            match self.socket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    return serde_json::from_str(&text).context("failed to deserialize message");
+                }
+                Some(Ok(Message::Close(_))) | None => bail!("connection closed"),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn handle(&mut self, msg: ServerMsg) -> Result<()> {
+        match msg {
+            ServerMsg::Hello { version, features } => {
+                info!("server speaks protocol version {version}, features {features:?}");
+            }
+            ServerMsg::Identity(id) => self.id = id,
+            ServerMsg::Snapshot { .. } => bail!("unexpected snapshot after connecting"),
+            ServerMsg::History { start, operations } => {
+                let operations = operations
+                    .into_iter()
+                    .map(|UserOperation { id, operation }| (id, operation));
+                let mut state = self.state.clone();
+                let (remote, send) = state
+                    .apply_history_raw(self.id, start, operations)
+                    .context("failed to apply history")?;
+                let mut text = self.text.clone();
+                for (_, operation) in &remote {
+                    text = operation
+                        .apply(&text)
+                        .context("remote edit does not apply to the local text")?;
+                }
+                self.state = state;
+                self.text = text;
+                if let Some(operation) = send {
+                    self.queue_edit(operation);
+                }
+                self.events.extend(
+                    remote
+                        .into_iter()
+                        .map(|(id, operation)| Event::Edit { id, operation }),
+                );
+            }
+            ServerMsg::Language(language) => self.events.push_back(Event::Language(language)),
+            ServerMsg::UserInfo { id, info } => self.events.push_back(Event::UserInfo { id, info }),
+            ServerMsg::UserCursor { id, data } => {
+                self.events.push_back(Event::UserCursor { id, data })
+            }
+            ServerMsg::Error(error) => {
+                warn!("server rejected message: {error}");
+                self.events.push_back(Event::Error(error));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! Messages exchanged with the server over WebSocket, encoded as JSON.
+
+use rustpad_wasm::OpSeq;
+use serde::{Deserialize, Serialize};
+
+/// Information about a user editing the document.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    /// Display name of the user.
+    pub name: String,
+    /// Hue of the user's cursor color, in degrees.
+    pub hue: u32,
+}
+
+/// Cursor and selection positions of a user, in Unicode codepoints.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorData {
+    /// Positions of each cursor.
+    pub cursors: Vec<u32>,
+    /// Start and end positions of each selection.
+    pub selections: Vec<(u32, u32)>,
+}
+
+/// An operation applied by a user, as sent in the history.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct UserOperation {
+    pub id: u64,
+    pub operation: OpSeq,
+}
+
+/// A message sent to the server.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) enum ClientMsg {
+    Edit { revision: usize, operation: OpSeq },
+    SetLanguage(String),
+    ClientInfo(UserInfo),
+    CursorData(CursorData),
+}
+
+/// A message received from the server.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) enum ServerMsg {
+    Hello {
+        version: u32,
+        features: Vec<String>,
+    },
+    Identity(u64),
+    Snapshot {
+        revision: usize,
+        text: String,
+    },
+    History {
+        start: usize,
+        operations: Vec<UserOperation>,
+    },
+    Language(String),
+    UserInfo {
+        id: u64,
+        info: Option<UserInfo>,
+    },
+    UserCursor {
+        id: u64,
+        data: CursorData,
+    },
+    Error(String),
+}
@@ -0,0 +1,113 @@
+//! Tests for the native client against a running server.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use rustpad_client::{Client, CursorData, Event, OpSeq, UserInfo};
+use rustpad_server::{server, ServerConfig};
+
+/// Serve a fresh server on an ephemeral port.
+fn spawn_server() -> SocketAddr {
+    let filter = server(ServerConfig::default());
+    let (addr, serve) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+    addr
+}
+
+#[tokio::test]
+async fn test_concurrent_edits() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let url = format!("ws://{}/api/socket/foobar", spawn_server());
+
+    let mut alice = Client::connect(&url).await?;
+    let mut bob = Client::connect(&url).await?;
+    assert_eq!(alice.text(), "");
+
+    let mut operation = OpSeq::new();
+    operation.insert("hello");
+    alice.edit(operation.clone()).await?;
+    alice.sync().await?;
+    assert_eq!(
+        bob.recv().await?,
+        Event::Edit {
+            id: alice.id(),
+            operation
+        }
+    );
+    assert_eq!(bob.text(), "hello");
+
+    // Both edit before seeing each other's change.
+    let mut operation = OpSeq::new();
+    operation.insert("<");
+    operation.retain(5);
+    alice.edit(operation).await?;
+    let mut operation = OpSeq::new();
+    operation.retain(5);
+    operation.insert(">");
+    bob.edit(operation).await?;
+    let mut operation = OpSeq::new();
+    operation.retain(6);
+    operation.insert("!");
+    bob.edit(operation).await?;
+
+    alice.sync().await?;
+    bob.sync().await?;
+    for _ in 0..2 {
+        assert!(matches!(alice.recv().await?, Event::Edit { .. }));
+    }
+    assert!(matches!(bob.recv().await?, Event::Edit { .. }));
+    assert_eq!(alice.text(), "<hello>!");
+    assert_eq!(bob.text(), "<hello>!");
+
+    let carol = Client::connect(&url).await?;
+    assert_eq!(carol.text(), "<hello>!");
+    assert_eq!(carol.revision(), alice.revision());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_presence() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let url = format!("ws://{}/api/socket/foobar", spawn_server());
+
+    let mut alice = Client::connect(&url).await?;
+    let mut bob = Client::connect(&url).await?;
+
+    let info = UserInfo {
+        name: "Alice".into(),
+        hue: 42,
+    };
+    alice.set_info(info.clone()).await?;
+    alice.set_language("rust").await?;
+    let user_info = Event::UserInfo {
+        id: alice.id(),
+        info: Some(info),
+    };
+    assert_eq!(bob.recv().await?, user_info);
+    assert_eq!(bob.recv().await?, Event::Language("rust".into()));
+
+    let data = CursorData {
+        cursors: vec![0],
+        selections: vec![],
+    };
+    bob.set_cursor(data.clone()).await?;
+    assert_eq!(alice.recv().await?, user_info);
+    assert_eq!(alice.recv().await?, Event::Language("rust".into()));
+    assert_eq!(
+        alice.recv().await?,
+        Event::UserCursor { id: bob.id(), data }
+    );
+
+    let bob_id = bob.id();
+    bob.close().await?;
+    assert_eq!(
+        alice.recv().await?,
+        Event::UserInfo {
+            id: bob_id,
+            info: None
+        }
+    );
+
+    Ok(())
+}
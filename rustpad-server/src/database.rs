@@ -6,12 +6,14 @@ use anyhow::{bail, Result};
 use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
 
 /// Represents a document persisted in database storage.
-#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug, Default)]
 pub struct PersistedDocument {
     /// Text content of the document.
     pub text: String,
     /// Language of the document for editor syntax highlighting.
     pub language: Option<String>,
+    /// ID of the document that this document was forked from, if any.
+    pub parent: Option<String>,
 }
 
 /// A driver for database operations wrapping a pool connection.
@@ -38,7 +40,7 @@ impl Database {
 
     /// Load the text of a document from the database.
     pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
-        sqlx::query_as(r#"SELECT text, language FROM document WHERE id = $1"#)
+        sqlx::query_as(r#"SELECT text, language, parent FROM document WHERE id = $1"#)
             .bind(document_id)
             .fetch_one(&self.pool)
             .await
@@ -50,16 +52,18 @@ impl Database {
         let result = sqlx::query(
             r#"
 INSERT INTO
-    document (id, text, language)
+    document (id, text, language, parent)
 VALUES
-    ($1, $2, $3)
+    ($1, $2, $3, $4)
 ON CONFLICT(id) DO UPDATE SET
     text = excluded.text,
-    language = excluded.language"#,
+    language = excluded.language,
+    parent = excluded.parent"#,
         )
         .bind(document_id)
         .bind(&document.text)
         .bind(&document.language)
+        .bind(&document.parent)
         .execute(&self.pool)
         .await?;
         if result.rows_affected() != 1 {
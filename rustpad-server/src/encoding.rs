@@ -0,0 +1,98 @@
+//! Wire encodings for messages exchanged over WebSocket.
+
+use anyhow::{Context, Result};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use warp::{sse, ws::Message};
+
+/// Encoding of messages sent by the server, negotiated when connecting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+pub enum Encoding {
+    /// JSON in text frames.
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// MessagePack in binary frames, with the same structure as JSON.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// JSON in text frames, except that messages of at least
+    /// [`COMPRESSION_THRESHOLD`] bytes are compressed with zlib and sent in
+    /// binary frames.
+    #[serde(rename = "json-deflate")]
+    DeflateJson,
+    /// Server-sent events for followers, in text frames holding the name of
+    /// the message type on the first line and its contents as JSON after it.
+    /// Only used by the server, so that events are built once per message.
+    #[serde(skip)]
+    Events,
+}
+
+/// Size in bytes from which messages are compressed, for encodings that
+/// support compression. Smaller messages barely shrink.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Compression level passed to zlib, trading off speed for size.
+const COMPRESSION_LEVEL: u8 = 6;
+
+impl Encoding {
+    /// Number of encodings, for tables indexed by encoding.
+    pub const COUNT: usize = 4;
+
+    /// Returns the name of the encoding, as given when connecting.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::DeflateJson => "json-deflate",
+            Encoding::Events => "events",
+        }
+    }
+
+    /// Encode a message into a WebSocket frame.
+    pub fn encode<T: Serialize>(self, msg: &T) -> Message {
+        match self {
+            Encoding::Json => Message::text(serde_json::to_string(msg).expect("failed serialize")),
+            Encoding::MessagePack => {
+                Message::binary(rmp_serde::to_vec_named(msg).expect("failed serialize"))
+            }
+            Encoding::DeflateJson => {
+                let json = serde_json::to_string(msg).expect("failed serialize");
+                if json.len() < COMPRESSION_THRESHOLD {
+                    Message::text(json)
+                } else {
+                    Message::binary(compress_to_vec_zlib(json.as_bytes(), COMPRESSION_LEVEL))
+                }
+            }
+            Encoding::Events => {
+                let msg = serde_json::to_value(msg).expect("failed serialize");
+                let (kind, data) = msg
+                    .as_object()
+                    .and_then(|msg| msg.iter().next())
+                    .expect("message is not an enum variant");
+                Message::text(format!("{kind}\n{data}"))
+            }
+        }
+    }
+}
+
+/// Convert a frame in the [`Encoding::Events`] encoding to a server-sent event.
+pub fn to_event(message: &Message) -> Option<sse::Event> {
+    let (kind, data) = message.to_str().ok()?.split_once('\n')?;
+    Some(sse::Event::default().event(kind).data(data))
+}
+
+/// Decode a message from a client, using JSON for text frames and MessagePack
+/// for binary frames. Returns `None` for control frames.
+pub fn decode<T: DeserializeOwned>(message: &Message) -> Result<Option<T>> {
+    if message.is_text() {
+        serde_json::from_slice(message.as_bytes())
+            .map(Some)
+            .context("failed to deserialize message")
+    } else if message.is_binary() {
+        rmp_serde::from_slice(message.as_bytes())
+            .map(Some)
+            .context("failed to deserialize binary message")
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,356 @@
+//! Languages supported by the editor, and detecting them from files.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+/// Extension and MIME type of the files of each language, by the language ID
+/// used by the editor.
+const FILE_TYPES: &[(&str, &str, &str)] = &[
+    ("plaintext", "txt", "text/plain"),
+    ("bat", "bat", "text/plain"),
+    ("c", "c", "text/x-c"),
+    ("clojure", "clj", "text/x-clojure"),
+    ("coffeescript", "coffee", "text/coffeescript"),
+    ("cpp", "cpp", "text/x-c++"),
+    ("csharp", "cs", "text/x-csharp"),
+    ("css", "css", "text/css"),
+    ("dart", "dart", "text/x-dart"),
+    ("dockerfile", "dockerfile", "text/plain"),
+    ("elixir", "ex", "text/x-elixir"),
+    ("fsharp", "fs", "text/x-fsharp"),
+    ("go", "go", "text/x-go"),
+    ("graphql", "graphql", "application/graphql"),
+    ("handlebars", "hbs", "text/x-handlebars-template"),
+    ("hcl", "hcl", "text/plain"),
+    ("html", "html", "text/html"),
+    ("ini", "ini", "text/plain"),
+    ("java", "java", "text/x-java"),
+    ("javascript", "js", "text/javascript"),
+    ("json", "json", "application/json"),
+    ("julia", "jl", "text/x-julia"),
+    ("kotlin", "kt", "text/x-kotlin"),
+    ("less", "less", "text/x-less"),
+    ("lua", "lua", "text/x-lua"),
+    ("markdown", "md", "text/markdown"),
+    ("mysql", "sql", "application/sql"),
+    ("objective-c", "m", "text/x-objectivec"),
+    ("pascal", "pas", "text/x-pascal"),
+    ("perl", "pl", "text/x-perl"),
+    ("pgsql", "sql", "application/sql"),
+    ("php", "php", "application/x-httpd-php"),
+    ("powershell", "ps1", "text/plain"),
+    ("proto", "proto", "text/plain"),
+    ("python", "py", "text/x-python"),
+    ("r", "r", "text/x-r"),
+    ("restructuredtext", "rst", "text/x-rst"),
+    ("ruby", "rb", "text/x-ruby"),
+    ("rust", "rs", "text/rust"),
+    ("scala", "scala", "text/x-scala"),
+    ("scheme", "scm", "text/x-scheme"),
+    ("scss", "scss", "text/x-scss"),
+    ("shell", "sh", "application/x-sh"),
+    ("sql", "sql", "application/sql"),
+    ("swift", "swift", "text/x-swift"),
+    ("systemverilog", "sv", "text/plain"),
+    ("tcl", "tcl", "text/x-tcl"),
+    ("typescript", "ts", "application/typescript"),
+    ("verilog", "v", "text/plain"),
+    ("vb", "vb", "text/x-vb"),
+    ("xml", "xml", "application/xml"),
+    ("yaml", "yaml", "application/yaml"),
+];
+
+/// Returns the file extension and MIME type for a language, falling back to
+/// plain text for unknown languages.
+pub fn file_type(language: Option<&str>) -> (&'static str, &'static str) {
+    let (_, extension, mime) = FILE_TYPES
+        .iter()
+        .find(|(id, _, _)| Some(*id) == language)
+        .unwrap_or(&FILE_TYPES[0]);
+    (extension, mime)
+}
+
+/// IDs of every language supported by the editor, separated by spaces.
+const LANGUAGES: &str = "\
+    plaintext abap aes apex azcli bat bicep c cameligo clojure coffeescript cpp csharp csp \
+    css cypher dart dockerfile ecl elixir flow9 freemarker2 fsharp go graphql handlebars \
+    hcl html ini java javascript json julia kotlin less lexon liquid lua m3 markdown mdx \
+    mips msdax mysql objective-c pascal pascaligo perl pgsql php pla postiats powerquery \
+    powershell proto pug python qsharp r razor redis redshift restructuredtext ruby rust \
+    sb scala scheme scss shell sol sparql sql st swift systemverilog tcl twig typescript \
+    typespec vb verilog wgsl xml yaml";
+
+/// Other names that languages are commonly known by, with their IDs.
+const ALIASES: &[(&str, &str)] = &[
+    ("c#", "csharp"),
+    ("docker", "dockerfile"),
+    ("f#", "fsharp"),
+    ("golang", "go"),
+    ("objc", "objective-c"),
+    ("plain", "plaintext"),
+    ("postgres", "pgsql"),
+    ("protobuf", "proto"),
+    ("solidity", "sol"),
+    ("text", "plaintext"),
+];
+
+/// Maximum length of a language name.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// File extensions that are not the main extension of their language, or that
+/// are shared by several languages, with the language they are detected as.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("bash", "shell"),
+    ("c++", "cpp"),
+    ("cc", "cpp"),
+    ("cjs", "javascript"),
+    ("cxx", "cpp"),
+    ("exs", "elixir"),
+    ("h", "c"),
+    ("hpp", "cpp"),
+    ("htm", "html"),
+    ("jsx", "javascript"),
+    ("kts", "kotlin"),
+    ("mjs", "javascript"),
+    ("psm1", "powershell"),
+    ("pyw", "python"),
+    ("sql", "sql"),
+    ("svg", "xml"),
+    ("tsx", "typescript"),
+    ("yml", "yaml"),
+    ("zsh", "shell"),
+];
+
+/// Interpreters named in shebang lines, with the language of their scripts.
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("bash", "shell"),
+    ("deno", "typescript"),
+    ("elixir", "elixir"),
+    ("julia", "julia"),
+    ("lua", "lua"),
+    ("node", "javascript"),
+    ("perl", "perl"),
+    ("php", "php"),
+    ("pwsh", "powershell"),
+    ("python", "python"),
+    ("Rscript", "r"),
+    ("ruby", "ruby"),
+    ("sh", "shell"),
+    ("tclsh", "tcl"),
+    ("ts-node", "typescript"),
+    ("zsh", "shell"),
+];
+
+/// Words that are common in each language, separated by spaces, and scored
+/// once for each of the first few times they appear.
+const KEYWORDS: &[(&str, &str)] = &[
+    ("c", "include int char void printf sizeof malloc free NULL unsigned typedef define"),
+    ("cpp", "include std cout endl template namespace class vector auto nullptr typename"),
+    ("csharp", "using namespace public class static void Console var string get set WriteLine"),
+    ("css", "color margin padding px display font background border width height"),
+    ("go", "func package fmt chan defer nil struct interface go err make"),
+    ("html", "div span body head html class href script"),
+    ("java", "public class static void private protected extends implements new System String final throws"),
+    ("javascript", "function const var let console require undefined null this async await export document typeof"),
+    ("php", "echo function array public foreach isset"),
+    ("python", "def elif self None import lambda pass print range __init__ __name__ True False except"),
+    ("ruby", "def end puts require elsif do nil attr_accessor module unless each"),
+    ("rust", "fn let mut impl pub struct enum match crate Some Ok Err Vec usize Self"),
+    ("shell", "echo fi then esac done export local exit grep"),
+    ("sql", "SELECT FROM WHERE INSERT INTO UPDATE JOIN CREATE TABLE VALUES ORDER GROUP BY"),
+    ("typescript", "interface readonly implements namespace declare keyof unknown string number boolean"),
+];
+
+/// Character sequences that are characteristic of each language, and scored
+/// twice as much as keywords.
+const MARKERS: &[(&str, &[&str])] = &[
+    ("c", &["#include <", "->", "#define "]),
+    ("cpp", &["std::", "#include <", "<<"]),
+    ("csharp", &["Console.", "{ get;"]),
+    ("css", &["px;", ": #"]),
+    ("go", &[":=", "func (", "if err != nil"]),
+    ("html", &["</", "<div", "/>"]),
+    ("java", &["System.out", "@Override"]),
+    ("javascript", &["=>", "===", "!=="]),
+    ("markdown", &["\n# ", "\n## ", "```", "\n- ", "](", "**"]),
+    ("php", &["<?php", "$this->"]),
+    ("python", &["):\n", "\"\"\""]),
+    ("ruby", &["do |", "end\n"]),
+    ("rust", &["::", "->", "#[", "&mut ", "!("]),
+    ("shell", &["$(", "${", "fi\n", ";;"]),
+    ("typescript", &[": string", ": number", ": boolean", "=>"]),
+];
+
+/// Number of times that a keyword or marker is scored at most, so that the
+/// score reflects the variety of keywords rather than repetition.
+const MAX_OCCURRENCES: usize = 3;
+
+/// Minimum score for a language to be detected from keywords.
+const MIN_SCORE: usize = 8;
+
+/// Number of bytes at the start of a text that keywords are counted in, or
+/// that are parsed as JSON.
+const SCAN_PREFIX: usize = 16 * 1024;
+
+/// Detect the language of a file from its name, if known, or its contents.
+pub fn detect(filename: Option<&str>, text: &str) -> Option<&'static str> {
+    filename
+        .and_then(from_filename)
+        .or_else(|| from_shebang(text))
+        .or_else(|| from_modeline(text))
+        .or_else(|| from_prologue(text))
+        .or_else(|| from_keywords(text))
+}
+
+/// Returns the ID of a known language from its name, which may differ in case
+/// or be an alias, a file extension or an interpreter.
+pub fn normalize(name: &str) -> Result<&'static str> {
+    if name.len() > MAX_NAME_LENGTH {
+        bail!("language name is longer than {MAX_NAME_LENGTH} bytes");
+    }
+    resolve(name.trim()).ok_or_else(|| anyhow!("unknown language {name:?}"))
+}
+
+/// Returns the ID of a language from a name used by other editors, such as
+/// a language ID, a file extension or an interpreter.
+fn resolve(name: &str) -> Option<&'static str> {
+    LANGUAGES
+        .split_whitespace()
+        .map(|id| (id, id))
+        .chain(ALIASES.iter().copied())
+        .chain(EXTENSIONS.iter().copied())
+        .chain(FILE_TYPES.iter().map(|&(id, ext, _)| (ext, id)))
+        .chain(INTERPRETERS.iter().copied())
+        .find(|&(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, id)| id)
+}
+
+/// Detect a language from the name of a file.
+fn from_filename(filename: &str) -> Option<&'static str> {
+    let path = Path::new(filename);
+    if path.file_name()? == "Dockerfile" {
+        return Some("dockerfile");
+    }
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .map(|&(ext, id)| (id, ext))
+        .chain(FILE_TYPES.iter().map(|&(id, ext, _)| (id, ext)))
+        .find(|&(_, ext)| ext == extension)
+        .map(|(id, _)| id)
+}
+
+/// Detect a language from the interpreter in the shebang line of a script,
+/// such as `#!/usr/bin/env python3`.
+fn from_shebang(text: &str) -> Option<&'static str> {
+    let line = text.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    INTERPRETERS
+        .iter()
+        .find(|&&(name, _)| name == program)
+        .map(|&(_, id)| id)
+}
+
+/// Detect a language from a Vim or Emacs modeline in the first or last lines
+/// of a text, such as `# vim: set ft=python:` or `// -*- mode: rust -*-`.
+fn from_modeline(text: &str) -> Option<&'static str> {
+    let mut edges = text.lines().take(5).chain(text.lines().rev().take(5));
+    edges.find_map(|line| {
+        let name = if let Some((_, rest)) = line.split_once("-*-") {
+            let (options, _) = rest.split_once("-*-")?;
+            match options.split_once("mode:") {
+                Some((_, mode)) => mode.split(';').next()?,
+                None => options,
+            }
+        } else {
+            let (_, options) = ["vim:", "vi:", "ex:"]
+                .iter()
+                .find_map(|prefix| line.split_once(prefix))?;
+            options
+                .split(|c: char| c == ':' || c.is_whitespace())
+                .find_map(|option| {
+                    let (key, value) = option.split_once('=')?;
+                    matches!(key, "ft" | "filetype" | "syntax").then_some(value)
+                })?
+        };
+        resolve(name.trim())
+    })
+}
+
+/// Detect a language from a distinctive start of the text.
+fn from_prologue(text: &str) -> Option<&'static str> {
+    let start = text.trim_start();
+    if start.starts_with("<?xml") {
+        Some("xml")
+    } else if start
+        .get(..14)
+        .is_some_and(|s| s.eq_ignore_ascii_case("<!doctype html"))
+    {
+        Some("html")
+    } else if (start.starts_with('{') || start.starts_with('[')) && is_json_prefix(text) {
+        Some("json")
+    } else {
+        None
+    }
+}
+
+/// Check that the start of a text is valid JSON, which is only cut short if
+/// the text is longer than the part that is parsed.
+fn is_json_prefix(text: &str) -> bool {
+    let start = prefix(text);
+    match serde_json::from_str::<serde::de::IgnoredAny>(start) {
+        Ok(_) => true,
+        Err(err) => err.is_eof() && start.len() < text.len(),
+    }
+}
+
+/// Detect a language from the frequency of keywords and characteristic
+/// character sequences, if one language scores clearly higher than others.
+fn from_keywords(text: &str) -> Option<&'static str> {
+    let text = prefix(text);
+
+    let mut words: HashMap<&str, usize> = HashMap::new();
+    for word in text.split(|c: char| !c.is_alphanumeric() && c != '_') {
+        *words.entry(word).or_default() += 1;
+    }
+    let mut scores: HashMap<&'static str, usize> = HashMap::new();
+    for &(id, keywords) in KEYWORDS {
+        let score: usize = keywords
+            .split_whitespace()
+            .map(|keyword| words.get(keyword).map_or(0, |&n| n.min(MAX_OCCURRENCES)))
+            .sum();
+        *scores.entry(id).or_default() += score;
+    }
+    for &(id, markers) in MARKERS {
+        let score: usize = markers
+            .iter()
+            .map(|marker| 2 * text.matches(marker).take(MAX_OCCURRENCES).count())
+            .sum();
+        *scores.entry(id).or_default() += score;
+    }
+
+    let mut scores: Vec<(usize, &'static str)> =
+        scores.into_iter().map(|(id, score)| (score, id)).collect();
+    scores.sort_unstable_by(|a, b| b.cmp(a));
+    match scores[..] {
+        // The best language must score half again as much as the runner-up.
+        [(best, id), (second, _), ..] if best >= MIN_SCORE && 2 * best >= 3 * second => Some(id),
+        _ => None,
+    }
+}
+
+/// Get the start of a text that is scanned for keywords or parsed as JSON.
+fn prefix(text: &str) -> &str {
+    let mut end = text.len().min(SCAN_PREFIX);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
@@ -3,21 +3,48 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use dashmap::DashMap;
-use log::{error, info};
+use dashmap::{mapref::one::RefMut, DashMap};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use log::{error, info, warn};
+use operational_transform::OperationSeq;
+use parking_lot::Mutex;
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
 use tokio::time::{self, Instant};
-use warp::{filters::BoxedFilter, ws::Ws, Filter, Rejection, Reply};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use warp::{
+    filters::{path::FullPath, BoxedFilter},
+    http::{header, Response, StatusCode, Uri},
+    hyper::body::Bytes,
+    ws::Ws,
+    Filter, Rejection, Reply,
+};
 
-use crate::{database::Database, rustpad::Rustpad};
+use crate::database::{Database, PersistedDocument};
+use crate::encoding::Encoding;
+use crate::limits::{LruIndex, MemoryUsage, Slot};
+use crate::ratelimit::{IpBuckets, RateLimit, Throttle};
+use crate::rustpad::{ConnectionOptions, Heartbeat, Rustpad};
+use crate::tls::RemoteAddr;
+use crate::webhook::Webhooks;
 
 pub mod database;
+mod encoding;
+mod language;
+mod limits;
 mod ot;
+pub mod ratelimit;
 mod rustpad;
+pub mod tls;
+mod webhook;
 
 /// An entry stored in the global server map.
 ///
@@ -27,13 +54,28 @@ mod rustpad;
 struct Document {
     last_accessed: Instant,
     rustpad: Arc<Rustpad>,
+    /// Number of live WebSocket connections to the document.
+    connections: Arc<AtomicUsize>,
+    /// Rate limit on changes made through the HTTP API, which share a bucket
+    /// as if they were messages from one more connection.
+    throttle: Throttle,
+    /// Completes when the persister of the document has stored it for the
+    /// last time, if it is persisted.
+    persister: Option<Persister>,
 }
 
+/// Completion of a document's persister, awaited by every request that opens
+/// the document again.
+type Persister = Shared<BoxFuture<'static, ()>>;
+
 impl Document {
-    fn new(rustpad: Arc<Rustpad>) -> Self {
+    fn new(rustpad: Arc<Rustpad>, throttle: Throttle) -> Self {
         Self {
             last_accessed: Instant::now(),
             rustpad,
+            connections: Default::default(),
+            throttle,
+            persister: None,
         }
     }
 }
@@ -55,8 +97,54 @@ impl warp::reject::Reject for CustomReject {}
 struct ServerState {
     /// Concurrent map storing in-memory documents.
     documents: Arc<DashMap<String, Document>>,
+    /// Documents that do not exist yet, opened by connections that have not
+    /// edited them. They are kept out of `documents` and its limits, and are
+    /// not persisted, until they are edited or assigned a language.
+    pending: Arc<DashMap<String, Document>>,
+    /// Persisters of documents removed from memory that have not stored them
+    /// for the last time yet, which finish before the documents are loaded.
+    closing: Arc<DashMap<String, Persister>>,
     /// Connection to the database pool, if persistence is enabled.
     database: Option<Database>,
+    /// Rate limit applied to messages from each connection.
+    connection_rate_limit: Option<RateLimit>,
+    /// Token buckets for messages from each remote address, if limited.
+    ip_buckets: Option<Arc<IpBuckets>>,
+    /// Number of live WebSocket connections across all documents.
+    connections: Arc<AtomicUsize>,
+    /// Limits on documents and connections.
+    limits: Limits,
+    /// Order in which documents were last used, for evicting them.
+    lru: Arc<Mutex<LruIndex>>,
+    /// Memory usage of all documents.
+    memory: Arc<MemoryUsage>,
+    /// Number of updates buffered for each connection.
+    update_capacity: usize,
+    /// Whether large messages are compressed for clients that request it.
+    compression: bool,
+    /// Pings sent to detect dead connections, if enabled.
+    heartbeat: Option<Heartbeat>,
+    /// Webhooks sent after documents are edited, if any.
+    webhooks: Option<Arc<Webhooks>>,
+}
+
+/// Resource limits copied from the server configuration.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    max_documents: Option<usize>,
+    memory_budget: Option<usize>,
+    evict_lru: bool,
+    max_connections: Option<usize>,
+    max_document_connections: Option<usize>,
+}
+
+impl Limits {
+    /// Returns whether a new document fits with `num_documents` others using
+    /// `memory_usage` bytes.
+    fn fit(&self, num_documents: usize, memory_usage: usize) -> bool {
+        self.max_documents.is_none_or(|max| num_documents < max)
+            && self.memory_budget.is_none_or(|max| memory_usage < max)
+    }
 }
 
 /// Statistics about the server, returned from an API endpoint.
@@ -68,8 +156,106 @@ struct Stats {
     num_documents: usize,
     /// Number of documents persisted in the database.
     database_size: usize,
+    /// Number of messages from clients waiting to be applied to documents.
+    queued_messages: usize,
+}
+
+/// Body of a request to the `/api/edit/{id}` endpoint.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EditRequest {
+    /// An operation on the text at a known revision, which is transformed
+    /// against any later edits.
+    Operation {
+        revision: usize,
+        operation: OperationSeq,
+    },
+    /// The new text of the document, which is diffed against the current text.
+    Text { text: String },
+}
+
+/// Response from the `/api/edit/{id}` endpoint.
+#[derive(Serialize)]
+struct EditResponse {
+    /// Revision of the document after the edit.
+    revision: usize,
+}
+
+/// Maximum size of a request to the `/api/edit/{id}` endpoint, in bytes.
+const MAX_EDIT_REQUEST_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Query parameters accepted when connecting to a document.
+#[derive(Deserialize)]
+struct SocketOptions {
+    /// Protocol version of the client, checked before sending any state.
+    version: Option<u32>,
+    /// Encoding of messages sent to the client.
+    #[serde(default)]
+    encoding: Encoding,
+    /// Whether to send the current text instead of the full history.
+    #[serde(default)]
+    snapshot: bool,
+}
+
+/// Query parameters accepted when uploading a file to a document.
+#[derive(Deserialize)]
+struct UploadOptions {
+    /// Language of the file, detected from its name or contents if not given.
+    language: Option<String>,
+    /// Name of the file, used to detect its language.
+    filename: Option<String>,
+}
+
+/// Response from the `PUT /api/text/{id}` endpoint.
+#[derive(Serialize)]
+struct UploadResponse {
+    /// Revision of the document after replacing its text.
+    revision: usize,
+    /// Language of the document, if given or detected.
+    language: Option<&'static str>,
+}
+
+/// Maximum size of an uploaded file, in bytes. The length of the text is
+/// further limited to 256 KiB characters when it is applied.
+const MAX_UPLOAD_SIZE: u64 = 1024 * 1024;
+
+/// Query parameters accepted when following a document.
+#[derive(Deserialize)]
+struct FollowOptions {
+    /// Whether to send the current text instead of the full history.
+    #[serde(default)]
+    snapshot: bool,
+}
+
+/// Query parameters accepted when forking a document.
+#[derive(Deserialize)]
+struct ForkOptions {
+    /// Revision of the text to copy, or the current revision if not given.
+    revision: Option<usize>,
 }
 
+/// Response from the `/api/document/{id}/fork` endpoint.
+#[derive(Serialize)]
+struct ForkResponse {
+    /// ID of the new document.
+    id: String,
+    /// Revision of the original document that the text was copied from.
+    revision: usize,
+}
+
+/// Metadata about a document, returned from the `/api/document/{id}` endpoint.
+#[derive(Serialize)]
+struct DocumentInfo {
+    /// Language of the document, if set.
+    language: Option<String>,
+    /// ID of the document that this document was forked from, if any.
+    parent: Option<String>,
+}
+
+/// Length of the random IDs given to forked documents, matching the IDs
+/// generated by the frontend.
+const DOCUMENT_ID_LENGTH: usize = 12;
+
 /// Server configuration.
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -77,6 +263,48 @@ pub struct ServerConfig {
     pub expiry_days: u32,
     /// Database object, for persistence if desired.
     pub database: Option<Database>,
+    /// URL prefix that all routes are served under, such as `/tools/pad/`.
+    pub base_path: String,
+    /// Rate limit for messages sent over each WebSocket connection.
+    pub connection_rate_limit: Option<RateLimit>,
+    /// Rate limit for messages sent from each remote IP address, summed over
+    /// all of its connections.
+    pub ip_rate_limit: Option<RateLimit>,
+    /// Maximum number of documents kept in memory at once.
+    pub max_documents: Option<usize>,
+    /// Approximate memory budget in bytes for the text and history of all
+    /// documents kept in memory.
+    pub memory_budget: Option<usize>,
+    /// Whether to evict the least recently used documents without any
+    /// connections when `max_documents` or `memory_budget` is reached, instead
+    /// of refusing to open new documents.
+    pub evict_lru: bool,
+    /// Maximum number of simultaneous WebSocket connections to the server.
+    pub max_connections: Option<usize>,
+    /// Maximum number of simultaneous WebSocket connections to one document.
+    pub max_document_connections: Option<usize>,
+    /// Number of updates, such as edits and cursor movements, buffered for
+    /// each connection. Clients that fall further behind are sent the edits
+    /// they missed and the current state of all users in one batch.
+    pub update_capacity: usize,
+    /// Whether to compress large messages, such as the history sent to new
+    /// clients, for clients that request the `json-deflate` encoding. Those
+    /// clients receive uncompressed JSON when this is disabled.
+    pub compression: bool,
+    /// Interval between WebSocket pings sent to each client, or `None` to
+    /// disable pings.
+    pub ping_interval: Option<Duration>,
+    /// Time without hearing from a client, including replies to pings, after
+    /// which its connection is closed and the user is removed. This only takes
+    /// effect when pings are enabled.
+    pub ping_timeout: Duration,
+    /// URLs that are sent a POST request with the new revision, the changed
+    /// ranges and a hash of the text after a document is edited, over TLS
+    /// for `https` URLs.
+    pub webhooks: Vec<Uri>,
+    /// Time without edits to a document after which webhooks are sent, so
+    /// that each burst of typing results in one request.
+    pub webhook_delay: Duration,
 }
 
 impl Default for ServerConfig {
@@ -84,15 +312,58 @@ impl Default for ServerConfig {
         Self {
             expiry_days: 1,
             database: None,
+            base_path: String::from("/"),
+            connection_rate_limit: None,
+            ip_rate_limit: None,
+            max_documents: None,
+            memory_budget: None,
+            evict_lru: false,
+            max_connections: None,
+            max_document_connections: None,
+            update_capacity: rustpad::DEFAULT_UPDATE_CAPACITY,
+            compression: true,
+            ping_interval: Some(Duration::from_secs(15)),
+            ping_timeout: Duration::from_secs(45),
+            webhooks: Vec::new(),
+            webhook_delay: Duration::from_secs(2),
         }
     }
 }
 
 /// A combined filter handling all server routes.
 pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    warp::path("api")
-        .and(backend(config))
-        .or(frontend())
+    base_path(&config.base_path)
+        .and(
+            trailing_slash()
+                .or(warp::path("api").and(backend(config)))
+                .or(frontend()),
+        )
+        .boxed()
+}
+
+/// Construct a filter matching the segments of a URL prefix.
+fn base_path(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_owned())).boxed()
+        })
+}
+
+/// Redirect from a base path to the same path with a trailing slash, so that
+/// relative URLs in the frontend resolve underneath it.
+fn trailing_slash() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::path::full())
+        .and_then(|path: FullPath| async move {
+            if path.as_str().ends_with('/') {
+                return Err(warp::reject::not_found());
+            }
+            match format!("{}/", path.as_str()).parse::<Uri>() {
+                Ok(uri) => Ok(warp::redirect::permanent(uri)),
+                Err(_) => Err(warp::reject::not_found()),
+            }
+        })
         .boxed()
 }
 
@@ -105,21 +376,97 @@ fn frontend() -> BoxedFilter<(impl Reply,)> {
 fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
     let state = ServerState {
         documents: Default::default(),
+        pending: Default::default(),
+        closing: Default::default(),
         database: config.database,
+        connection_rate_limit: config.connection_rate_limit,
+        ip_buckets: config
+            .ip_rate_limit
+            .map(|limit| Arc::new(IpBuckets::new(limit))),
+        connections: Default::default(),
+        limits: Limits {
+            max_documents: config.max_documents,
+            memory_budget: config.memory_budget,
+            evict_lru: config.evict_lru,
+            max_connections: config.max_connections,
+            max_document_connections: config.max_document_connections,
+        },
+        lru: Default::default(),
+        memory: Default::default(),
+        update_capacity: config.update_capacity,
+        compression: config.compression,
+        heartbeat: config.ping_interval.map(|interval| Heartbeat {
+            interval,
+            timeout: config.ping_timeout,
+        }),
+        webhooks: (!config.webhooks.is_empty())
+            .then(|| Arc::new(Webhooks::new(config.webhooks, config.webhook_delay))),
     };
     tokio::spawn(cleaner(state.clone(), config.expiry_days));
+    if let (true, Some(memory_budget)) = (config.evict_lru, config.memory_budget) {
+        tokio::spawn(evictor(state.clone(), memory_budget));
+    }
 
     let state_filter = warp::any().map(move || state.clone());
+    // Connections accepted over TLS carry their address in an extension.
+    let remote = warp::addr::remote().and(warp::ext::optional()).map(
+        |addr: Option<SocketAddr>, tls: Option<RemoteAddr>| {
+            addr.or(tls.map(|RemoteAddr(addr)| addr))
+        },
+    );
 
     let socket = warp::path!("socket" / String)
+        .and(warp::query())
         .and(warp::ws())
+        .and(remote)
         .and(state_filter.clone())
         .and_then(socket_handler);
 
+    let edit = warp::path!("edit" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_EDIT_REQUEST_SIZE))
+        .and(warp::body::bytes())
+        .and(remote)
+        .and(state_filter.clone())
+        .and_then(edit_handler);
+
+    let events = warp::path!("events" / String)
+        .and(warp::get())
+        .and(warp::query())
+        .and(state_filter.clone())
+        .and_then(events_handler);
+
+    let upload = warp::path!("text" / String)
+        .and(warp::put())
+        .and(warp::query())
+        .and(warp::body::content_length_limit(MAX_UPLOAD_SIZE))
+        .and(warp::body::bytes())
+        .and(remote)
+        .and(state_filter.clone())
+        .and_then(upload_handler);
+
     let text = warp::path!("text" / String)
+        .and(warp::get())
         .and(state_filter.clone())
         .and_then(text_handler);
 
+    let download = warp::path!("download" / String)
+        .and(warp::header::optional("if-none-match"))
+        .and(state_filter.clone())
+        .and_then(download_handler);
+
+    let fork = warp::path!("document" / String / "fork")
+        .and(warp::post())
+        .and(warp::query())
+        .and(remote)
+        .and(state_filter.clone())
+        .and_then(fork_handler);
+
+    let document = warp::path!("document" / String)
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(document_handler);
+
     let start_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("SystemTime returned before UNIX_EPOCH")
@@ -129,55 +476,661 @@ fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
         .and(state_filter)
         .and_then(stats_handler);
 
-    socket.or(text).or(stats).boxed()
+    socket
+        .or(edit)
+        .or(events)
+        .or(upload)
+        .or(text)
+        .or(download)
+        .or(fork)
+        .or(document)
+        .or(stats)
+        .boxed()
 }
 
 /// Handler for the `/api/socket/{id}` endpoint.
-async fn socket_handler(id: String, ws: Ws, state: ServerState) -> Result<impl Reply, Rejection> {
-    use dashmap::mapref::entry::Entry;
-
+async fn socket_handler(
+    id: String,
+    options: SocketOptions,
+    ws: Ws,
+    addr: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
     info!("socket connection for id = {}", id);
 
-    let mut entry = match state.documents.entry(id.clone()) {
-        Entry::Occupied(e) => e.into_ref(),
-        Entry::Vacant(e) => {
-            let rustpad = Arc::new(match &state.database {
-                Some(db) => db.load(&id).await.map(Rustpad::from).unwrap_or_default(),
-                None => Rustpad::default(),
-            });
-            if let Some(db) = &state.database {
-                tokio::spawn(persister(id, Arc::clone(&rustpad), db.clone()));
+    let unavailable = |reason: &'static str| {
+        warn!("rejecting socket connection for id = {}: {}", id, reason);
+        Ok(warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE).into_response())
+    };
+
+    let Some(server_slot) = Slot::acquire(&state.connections, state.limits.max_connections) else {
+        return unavailable("too many connections to this server");
+    };
+    let Some(entry) = open_document(&state, &id, false).await else {
+        return unavailable("too many documents open on this server");
+    };
+    let rustpad = Arc::clone(&entry.rustpad);
+    let document_slot = Slot::acquire(&entry.connections, state.limits.max_document_connections);
+    drop(entry);
+    let Some(document_slot) = document_slot else {
+        return unavailable("too many connections to this document");
+    };
+
+    let ip = state
+        .ip_buckets
+        .clone()
+        .zip(addr)
+        .map(|(b, addr)| (b, addr.ip()));
+    let throttle = Throttle::new(state.connection_rate_limit, ip);
+    let encoding = match options.encoding {
+        Encoding::DeflateJson if !state.compression => Encoding::Json,
+        encoding => encoding,
+    };
+    let options = ConnectionOptions {
+        version: options.version,
+        encoding,
+        snapshot: options.snapshot,
+        throttle,
+        heartbeat: state.heartbeat,
+    };
+    let reply = ws.on_upgrade(move |socket| async move {
+        rustpad.on_connection(socket, options).await;
+        drop((server_slot, document_slot));
+
+        // Visitors who never edit, such as crawlers and link previews, should
+        // not leave empty documents behind after they disconnect.
+        discard_untouched(&state, &id, &rustpad);
+    });
+    Ok(reply.into_response())
+}
+
+/// Handler for the `/api/events/{id}` endpoint.
+async fn events_handler(
+    id: String,
+    options: FollowOptions,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    info!("event stream for id = {}", id);
+
+    let unavailable = |reason: &'static str| {
+        warn!("rejecting event stream for id = {}: {}", id, reason);
+        Ok(warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE).into_response())
+    };
+
+    let Some(server_slot) = Slot::acquire(&state.connections, state.limits.max_connections) else {
+        return unavailable("too many connections to this server");
+    };
+    let Some(entry) = open_document(&state, &id, false).await else {
+        return unavailable("too many documents open on this server");
+    };
+    let rustpad = Arc::clone(&entry.rustpad);
+    let document_slot = Slot::acquire(&entry.connections, state.limits.max_document_connections);
+    drop(entry);
+    let Some(document_slot) = document_slot else {
+        return unavailable("too many connections to this document");
+    };
+
+    let (events, rx) = mpsc::channel(state.update_capacity);
+    tokio::spawn(async move {
+        rustpad.on_follower(events, options.snapshot).await;
+        drop((server_slot, document_slot));
+        discard_untouched(&state, &id, &rustpad);
+    });
+    let stream = ReceiverStream::new(rx).map(Ok::<_, Infallible>);
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response())
+}
+
+/// Returns the entry for a document, loading it from the database or creating
+/// it if it is not in memory. Returns `None` if there is no room for another
+/// document within the configured limits.
+///
+/// Documents that do not exist are only created if `create` is true, and are
+/// otherwise kept pending until they are first edited, so that visitors who
+/// never edit do not take up room.
+async fn open_document<'a>(
+    state: &'a ServerState,
+    id: &str,
+    create: bool,
+) -> Option<RefMut<'a, String, Document>> {
+    let mut loaded: Option<Option<PersistedDocument>> = None;
+    loop {
+        {
+            // Documents are only added and removed with the index locked, so
+            // that concurrent requests cannot exceed the limits together.
+            let mut lru = state.lru.lock();
+            if let Some(mut entry) = state.documents.get_mut(id) {
+                lru.touch(id);
+                entry.last_accessed = Instant::now();
+                return Some(entry);
+            }
+            if let Some(document) = loaded.take() {
+                if document.is_none() && !create {
+                    if !state.pending.contains_key(id) && !has_room(state) {
+                        return None;
+                    }
+                    let entry = state.pending.entry(id.to_owned()).or_insert_with(|| {
+                        let document = new_pending_document(state);
+                        let rustpad = Arc::clone(&document.rustpad);
+                        tokio::spawn(admit_when_touched(state.clone(), id.to_owned(), rustpad));
+                        document
+                    });
+                    return Some(entry);
+                }
+                if !make_room(state, &mut lru) {
+                    return None;
+                }
+                lru.touch(id);
+                let document = match state.pending.remove(id) {
+                    Some((_, pending)) => admit(state, id, pending, 0),
+                    None => new_document(state, id, document),
+                };
+                state.documents.insert(id.to_owned(), document);
+                continue;
             }
-            e.insert(Document::new(rustpad))
+            // Pending documents are known not to be in the database.
+            if state.pending.contains_key(id) {
+                loaded = Some(None);
+                continue;
+            }
+        }
+        loaded = Some(load_document(state, id).await);
+    }
+}
+
+/// Load a document that is not in memory from the database, if it is stored,
+/// once it has been stored for the last time after being closed.
+async fn load_document(state: &ServerState, id: &str) -> Option<PersistedDocument> {
+    let db = state.database.as_ref()?;
+    let persister = state.closing.get(id).map(|entry| entry.clone());
+    if let Some(persister) = persister {
+        persister.await;
+    }
+    db.load(id).await.ok()
+}
+
+/// Construct a document, optionally from persisted storage, and spawn the
+/// tasks that persist it and send webhooks for it.
+fn new_document(state: &ServerState, id: &str, document: Option<PersistedDocument>) -> Document {
+    let memory = Arc::clone(&state.memory);
+    let rustpad = Arc::new(Rustpad::new(state.update_capacity, document, memory));
+    let revision = rustpad.revision();
+    let document = Document::new(rustpad, Throttle::new(state.connection_rate_limit, None));
+    admit(state, id, document, revision)
+}
+
+/// Construct an empty document that is not persisted until it is admitted.
+fn new_pending_document(state: &ServerState) -> Document {
+    let memory = Arc::clone(&state.memory);
+    let rustpad = Arc::new(Rustpad::new(state.update_capacity, None, memory));
+    Document::new(rustpad, Throttle::new(state.connection_rate_limit, None))
+}
+
+/// Spawn the tasks that persist a document and send webhooks for edits since
+/// `revision`, before it is inserted into the documents in memory.
+fn admit(state: &ServerState, id: &str, mut document: Document, revision: usize) -> Document {
+    let rustpad = &document.rustpad;
+    if let Some(db) = &state.database {
+        let persister = tokio::spawn(persister(id.to_owned(), Arc::clone(rustpad), db.clone()));
+        document.persister = Some(persister.map(|_| ()).boxed().shared());
+    }
+    if let Some(webhooks) = &state.webhooks {
+        let notifier = webhook::notifier(
+            id.to_owned(),
+            Arc::clone(rustpad),
+            Arc::clone(webhooks),
+            revision,
+        );
+        tokio::spawn(notifier);
+    }
+    document.last_accessed = Instant::now();
+    document
+}
+
+/// Move a pending document into the documents in memory once it is edited or
+/// assigned a language, or close it if there is no room for it.
+async fn admit_when_touched(state: ServerState, id: String, rustpad: Arc<Rustpad>) {
+    if !rustpad.touched().await {
+        return;
+    }
+    let mut lru = state.lru.lock();
+    let same = |_: &String, document: &Document| Arc::ptr_eq(&document.rustpad, &rustpad);
+    let Some((_, document)) = state.pending.remove_if(&id, same) else {
+        return; // Already admitted by a request that opened it.
+    };
+    // Only the number of documents is checked, as edits that grow documents
+    // past the memory budget evict others instead.
+    let max_documents = state.limits.max_documents;
+    if !evict(&state, &mut lru, |num_documents, _| {
+        max_documents.is_none_or(|max| num_documents < max)
+    }) {
+        warn!("closing new document id = {}: too many documents open", id);
+        return;
+    }
+    lru.touch(&id);
+    let document = admit(&state, &id, document, 0);
+    state.documents.insert(id, document);
+}
+
+/// Returns a document in memory, including one that is pending.
+fn find_document(state: &ServerState, id: &str) -> Option<Arc<Rustpad>> {
+    let rustpad = |document: &Document| Arc::clone(&document.rustpad);
+    match state.documents.get(id) {
+        Some(entry) => Some(rustpad(&entry)),
+        None => state.pending.get(id).map(|entry| rustpad(&entry)),
+    }
+}
+
+/// Returns whether a request from `addr` to change a document through the HTTP
+/// API is allowed, consuming a token if so. Requests share the bucket of their
+/// remote address with its WebSocket messages.
+fn allow_request(state: &ServerState, addr: Option<SocketAddr>) -> bool {
+    match (&state.ip_buckets, addr) {
+        (Some(buckets), Some(addr)) => buckets.try_take(addr.ip()),
+        _ => true,
+    }
+}
+
+/// Reply to a request that exceeded a rate limit.
+fn too_many_requests(id: &str) -> warp::reply::Response {
+    let reason = "rate limit exceeded";
+    warn!("rejecting request for id = {}: {}", id, reason);
+    warp::reply::with_status(reason, StatusCode::TOO_MANY_REQUESTS).into_response()
+}
+
+/// Remove a document if it has no connections and has never been edited.
+fn discard_untouched(state: &ServerState, id: &str, rustpad: &Arc<Rustpad>) {
+    let mut lru = state.lru.lock();
+    let untouched = |_: &String, document: &Document| {
+        Arc::ptr_eq(&document.rustpad, rustpad)
+            && document.connections.load(Ordering::Acquire) == 0
+            && rustpad.untouched()
+    };
+    let removed = state.documents.remove_if(id, untouched);
+    let removed = removed.or_else(|| state.pending.remove_if(id, untouched));
+    if removed.is_some() {
+        lru.remove(id);
+        info!("discarding untouched document id = {}", id);
+    }
+}
+
+/// Handler for the `/api/edit/{id}` endpoint.
+async fn edit_handler(
+    id: String,
+    body: Bytes,
+    addr: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    info!("edit request for id = {}", id);
+    // The body is JSON whatever its content type, which `curl -d` sets to a
+    // form by default.
+    let request: EditRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("rejecting edit request for id = {}: {}", id, e);
+            let reason = format!("invalid edit request: {e}");
+            return Ok(warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response());
         }
     };
+    if !allow_request(&state, addr) {
+        return Ok(too_many_requests(&id));
+    }
+    let Some(mut entry) = open_document(&state, &id, true).await else {
+        let reason = "too many documents open on this server";
+        return Ok(
+            warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE).into_response(),
+        );
+    };
+    let allowed = entry.throttle.allow();
+    let rustpad = Arc::clone(&entry.rustpad);
+    drop(entry);
+    if !allowed {
+        discard_untouched(&state, &id, &rustpad);
+        return Ok(too_many_requests(&id));
+    }
+
+    let result = match request {
+        EditRequest::Operation {
+            revision,
+            operation,
+        } => rustpad.apply_edit(revision, operation).await,
+        EditRequest::Text { text } => rustpad.replace_text(text, None).await,
+    };
+    Ok(match result {
+        Ok(revision) => warp::reply::json(&EditResponse { revision }).into_response(),
+        Err(e) => {
+            warn!("rejecting edit request for id = {}: {}", id, e);
+            let status = edit_error_status(&rustpad);
+            discard_untouched(&state, &id, &rustpad);
+            warp::reply::with_status(e.to_string(), status).into_response()
+        }
+    })
+}
+
+/// Returns the status of a failed edit, which is only the client's fault if the
+/// document is still open. Documents are closed when they expire or are evicted.
+fn edit_error_status(rustpad: &Rustpad) -> StatusCode {
+    match rustpad.killed() {
+        true => StatusCode::SERVICE_UNAVAILABLE,
+        false => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Check that a new document fits within the configured limits, evicting the
+/// least recently used documents without connections if enabled.
+fn make_room(state: &ServerState, lru: &mut LruIndex) -> bool {
+    let limits = state.limits;
+    evict(state, lru, |num_documents, memory_usage| {
+        limits.fit(num_documents, memory_usage)
+    })
+}
+
+/// Returns whether a new document fits within the configured limits, or may
+/// fit by evicting others once it is edited.
+fn has_room(state: &ServerState) -> bool {
+    let limits = state.limits;
+    limits.evict_lru || limits.fit(state.documents.len(), state.memory.get())
+}
+
+/// Evict the least recently used documents without connections until `fits`
+/// returns true for the number of documents and their memory usage, if
+/// enabled. Returns whether the documents fit.
+fn evict(state: &ServerState, lru: &mut LruIndex, fits: impl Fn(usize, usize) -> bool) -> bool {
+    let mut num_documents = state.documents.len();
+    let mut memory_usage = state.memory.get();
+    if fits(num_documents, memory_usage) {
+        return true;
+    }
+    if !state.limits.evict_lru {
+        return false;
+    }
+    let mut evicted = Vec::new();
+    for key in lru.iter() {
+        let idle =
+            |_: &String, document: &Document| document.connections.load(Ordering::Acquire) == 0;
+        if let Some((_, document)) = state.documents.remove_if(key, idle) {
+            info!("evicting least recently used document id = {}", key);
+            num_documents -= 1;
+            memory_usage = memory_usage.saturating_sub(document.rustpad.memory_usage());
+            close_document(state, key, document);
+            evicted.push(key.to_owned());
+            if fits(num_documents, memory_usage) {
+                break;
+            }
+        }
+    }
+    for key in &evicted {
+        lru.remove(key);
+    }
+    fits(num_documents, memory_usage)
+}
+
+/// Close a document that was removed from memory, keeping track of its
+/// persister until it has stored the document for the last time.
+fn close_document(state: &ServerState, id: &str, document: Document) {
+    if let Some(persister) = document.persister.clone() {
+        state.closing.insert(id.to_owned(), persister.clone());
+        let closing = Arc::clone(&state.closing);
+        let id = id.to_owned();
+        tokio::spawn(async move {
+            persister.clone().await;
+            closing.remove_if(&id, |_, entry| entry.ptr_eq(&persister));
+        });
+    }
+    drop(document);
+}
 
-    let value = entry.value_mut();
-    value.last_accessed = Instant::now();
-    let rustpad = Arc::clone(&value.rustpad);
-    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket).await }))
+/// Evicts documents when edits grow their memory usage past the budget, so
+/// that the budget is kept even when no new documents are opened.
+async fn evictor(state: ServerState, memory_budget: usize) {
+    loop {
+        state.memory.grown().await;
+        evict(&state, &mut state.lru.lock(), |_, memory_usage| {
+            memory_usage <= memory_budget
+        });
+    }
 }
 
 /// Handler for the `/api/text/{id}` endpoint.
 async fn text_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    Ok(match state.documents.get(&id) {
-        Some(value) => value.rustpad.text(),
+    let rustpad = find_document(&state, &id);
+    Ok(match rustpad {
+        Some(rustpad) => rustpad.text().await,
+        None => load_document(&state, &id)
+            .await
+            .map(|document| document.text)
+            .unwrap_or_default(),
+    })
+}
+
+/// Handler for the `PUT /api/text/{id}` endpoint.
+async fn upload_handler(
+    id: String,
+    options: UploadOptions,
+    body: Bytes,
+    addr: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    info!("upload for id = {}", id);
+    let bad_request = |reason: String| {
+        warn!("rejecting upload for id = {}: {}", id, reason);
+        Ok(warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response())
+    };
+    let Ok(text) = String::from_utf8(body.to_vec()) else {
+        return bad_request("file is not valid UTF-8".into());
+    };
+    let language = match options.language.as_deref().map(language::normalize) {
+        Some(Ok(language)) => Some(language),
+        Some(Err(e)) => return bad_request(e.to_string()),
+        None => language::detect(options.filename.as_deref(), &text),
+    };
+
+    if !allow_request(&state, addr) {
+        return Ok(too_many_requests(&id));
+    }
+    let Some(mut entry) = open_document(&state, &id, true).await else {
+        let reason = "too many documents open on this server";
+        return Ok(
+            warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE).into_response(),
+        );
+    };
+    let allowed = entry.throttle.allow();
+    let rustpad = Arc::clone(&entry.rustpad);
+    drop(entry);
+    if !allowed {
+        discard_untouched(&state, &id, &rustpad);
+        return Ok(too_many_requests(&id));
+    }
+
+    let revision = match rustpad.replace_text(text, language.map(String::from)).await {
+        Ok(revision) => revision,
+        Err(e) => {
+            warn!("rejecting upload for id = {}: {}", id, e);
+            let status = edit_error_status(&rustpad);
+            discard_untouched(&state, &id, &rustpad);
+            return Ok(warp::reply::with_status(e.to_string(), status).into_response());
+        }
+    };
+    Ok(warp::reply::json(&UploadResponse { revision, language }).into_response())
+}
+
+/// Handler for the `/api/download/{id}` endpoint.
+async fn download_handler(
+    id: String,
+    if_none_match: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let rustpad = find_document(&state, &id);
+    // Documents that are not in memory have no revision to derive a tag from,
+    // so stored documents are tagged with a hash of their contents instead.
+    let (etag, document) = match rustpad {
+        Some(rustpad) => {
+            let (revision, document) = rustpad.revision_snapshot().await;
+            let etag = rustpad.etag(revision, document.language.as_deref());
+            (Some(etag), document)
+        }
+        None => match load_document(&state, &id).await {
+            Some(document) => (Some(content_etag(&document)), document),
+            None => (None, PersistedDocument::default()),
+        },
+    };
+
+    let response = Response::builder().header(header::CACHE_CONTROL, "no-cache");
+    let response = match &etag {
+        Some(etag) => response.header(header::ETAG, etag),
+        None => response,
+    };
+    if let (Some(etag), Some(if_none_match)) = (&etag, if_none_match) {
+        let matches = if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+        });
+        if matches {
+            let response = response.status(StatusCode::NOT_MODIFIED);
+            return Ok(response.body(String::new()).expect("invalid response"));
+        }
+    }
+
+    let (extension, mime) = language::file_type(document.language.as_deref());
+    let name: String = id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let disposition = format!("attachment; filename=\"{name}.{extension}\"");
+    let response = response
+        .header(header::CONTENT_TYPE, format!("{mime}; charset=utf-8"))
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(document.text)
+        .expect("invalid response");
+    Ok(response)
+}
+
+/// Returns a tag identifying the text and language of a stored document.
+fn content_etag(document: &PersistedDocument) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(document.language.as_deref().unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(&document.text);
+    format!("\"{:x}\"", hasher.finalize())
+}
+
+/// Handler for the `/api/document/{id}/fork` endpoint.
+async fn fork_handler(
+    id: String,
+    options: ForkOptions,
+    addr: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    info!("fork request for id = {}", id);
+    let unavailable = |reason: &'static str| {
+        warn!("rejecting fork request for id = {}: {}", id, reason);
+        Ok(warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE).into_response())
+    };
+
+    if !allow_request(&state, addr) {
+        return Ok(too_many_requests(&id));
+    }
+    // Forks count against the rate limit of the original document.
+    let source = state
+        .documents
+        .get_mut(&id)
+        .map(|mut value| (Arc::clone(&value.rustpad), value.throttle.allow()));
+    let (rustpad, allowed) = match source {
+        Some(source) => source,
         None => {
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|document| document.text)
-                    .unwrap_or_default()
-            } else {
-                String::new()
+            // Opening a document that does not exist would create it.
+            if load_document(&state, &id).await.is_none() {
+                warn!("rejecting fork request for id = {}: not found", id);
+                let reply = warp::reply::with_status("document not found", StatusCode::NOT_FOUND);
+                return Ok(reply.into_response());
             }
+            let Some(mut entry) = open_document(&state, &id, true).await else {
+                return unavailable("too many documents open on this server");
+            };
+            let source = (Arc::clone(&entry.rustpad), entry.throttle.allow());
+            drop(entry);
+            source
         }
-    })
+    };
+    if !allowed {
+        discard_untouched(&state, &id, &rustpad);
+        return Ok(too_many_requests(&id));
+    }
+    let result = match options.revision {
+        Some(revision) => rustpad.snapshot_at(revision).await.map(|d| (revision, d)),
+        None => Ok(rustpad.revision_snapshot().await),
+    };
+    discard_untouched(&state, &id, &rustpad);
+    let (revision, mut document) = match result {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!("rejecting fork request for id = {}: {}", id, e);
+            let reply = warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
+    document.parent = Some(id.clone());
+
+    // The fork is admitted like any other new document, and stored by its
+    // persister once it is in memory.
+    let fork_id = loop {
+        let fork_id: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(DOCUMENT_ID_LENGTH)
+            .map(char::from)
+            .collect();
+        if let Some(db) = &state.database {
+            if db.load(&fork_id).await.is_ok() {
+                continue;
+            }
+        }
+        let mut lru = state.lru.lock();
+        if state.documents.contains_key(&fork_id) || state.pending.contains_key(&fork_id) {
+            continue;
+        }
+        if !make_room(&state, &mut lru) {
+            return unavailable("too many documents open on this server");
+        }
+        lru.touch(&fork_id);
+        let fork = new_document(&state, &fork_id, Some(document));
+        state.documents.insert(fork_id.clone(), fork);
+        break fork_id;
+    };
+    info!(
+        "forked revision {} of id = {} as id = {}",
+        revision, id, fork_id
+    );
+    let response = ForkResponse {
+        id: fork_id,
+        revision,
+    };
+    Ok(warp::reply::json(&response).into_response())
+}
+
+/// Handler for the `/api/document/{id}` endpoint.
+async fn document_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let rustpad = find_document(&state, &id);
+    let document = match rustpad {
+        Some(rustpad) => rustpad.snapshot().await,
+        None => load_document(&state, &id).await.unwrap_or_default(),
+    };
+    Ok(warp::reply::json(&DocumentInfo {
+        language: document.language,
+        parent: document.parent,
+    }))
 }
 
 /// Handler for the `/api/stats` endpoint.
 async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
     let num_documents = state.documents.len();
+    let queued_messages = state
+        .documents
+        .iter()
+        .map(|entry| entry.rustpad.queued_messages())
+        .sum();
     let database_size = match state.database {
         None => 0,
         Some(db) => match db.count().await {
@@ -189,6 +1142,7 @@ async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply
         start_time,
         num_documents,
         database_size,
+        queued_messages,
     }))
 }
 
@@ -205,8 +1159,16 @@ async fn cleaner(state: ServerState, expiry_days: u32) {
             }
         }
         info!("cleaner removing keys: {:?}", keys);
+        let mut lru = state.lru.lock();
         for key in keys {
-            state.documents.remove(&key);
+            if let Some((key, document)) = state.documents.remove(&key) {
+                close_document(&state, &key, document);
+            }
+            lru.remove(&key);
+        }
+        drop(lru);
+        if let Some(ip_buckets) = &state.ip_buckets {
+            ip_buckets.prune(HOUR);
         }
     }
 }
@@ -217,18 +1179,26 @@ const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
 /// Persists changed documents after a fixed time interval.
 async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database) {
     let mut last_revision = 0;
-    while !rustpad.killed() {
+    loop {
         let interval = PERSIST_INTERVAL
             + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
-        time::sleep(interval).await;
+        // Documents are stored one last time when they are closed, so that
+        // they are not loaded again from an older snapshot.
+        let closed = tokio::select! {
+            _ = time::sleep(interval) => false,
+            _ = rustpad.closed() => true,
+        };
         let revision = rustpad.revision();
         if revision > last_revision {
             info!("persisting revision {} for id = {}", revision, id);
-            if let Err(e) = db.store(&id, &rustpad.snapshot()).await {
+            if let Err(e) = db.store(&id, &rustpad.snapshot().await).await {
                 error!("when persisting document {}: {}", id, e);
             } else {
                 last_revision = revision;
             }
         }
+        if closed {
+            break;
+        }
     }
 }
@@ -0,0 +1,96 @@
+//! Counters for enforcing server-wide resource limits.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// A reserved slot in a shared counter, which is released when dropped.
+#[derive(Debug)]
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Reserve a slot if the counter is below `limit`, or unconditionally if
+    /// there is no limit.
+    pub fn acquire(counter: &Arc<AtomicUsize>, limit: Option<usize>) -> Option<Self> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| match limit {
+                Some(limit) if count >= limit => None,
+                _ => Some(count + 1),
+            })
+            .ok()?;
+        Some(Self(Arc::clone(counter)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Approximate memory used by the text and history of all documents, which
+/// each document updates as it changes.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    total: AtomicUsize,
+    grown: Notify,
+}
+
+impl MemoryUsage {
+    /// Record that a document changed from using `old` bytes to `new` bytes.
+    pub fn update(&self, old: usize, new: usize) {
+        if new > old {
+            self.total.fetch_add(new - old, Ordering::AcqRel);
+            self.grown.notify_one();
+        } else {
+            self.total.fetch_sub(old - new, Ordering::AcqRel);
+        }
+    }
+
+    /// Returns the total number of bytes used.
+    pub fn get(&self) -> usize {
+        self.total.load(Ordering::Acquire)
+    }
+
+    /// Wait until the total grows.
+    pub async fn grown(&self) {
+        self.grown.notified().await
+    }
+}
+
+/// IDs of the documents in memory, ordered from least to most recently used.
+#[derive(Debug, Default)]
+pub struct LruIndex {
+    order: BTreeMap<u64, String>,
+    ticks: HashMap<String, u64>,
+    clock: u64,
+}
+
+impl LruIndex {
+    /// Mark a document as the most recently used.
+    pub fn touch(&mut self, id: &str) {
+        self.clock += 1;
+        if let Some(tick) = self.ticks.get_mut(id) {
+            let id = self.order.remove(tick).expect("LRU index out of sync");
+            *tick = self.clock;
+            self.order.insert(self.clock, id);
+        } else {
+            self.ticks.insert(id.to_owned(), self.clock);
+            self.order.insert(self.clock, id.to_owned());
+        }
+    }
+
+    /// Remove a document from the index.
+    pub fn remove(&mut self, id: &str) {
+        if let Some(tick) = self.ticks.remove(id) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Returns the IDs of documents from least to most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.order.values().map(String::as_str)
+    }
+}
@@ -1,4 +1,34 @@
-use rustpad_server::{server, database::Database, ServerConfig};
+use std::{fmt::Debug, str::FromStr, time::Duration};
+
+use rustpad_server::{database::Database, server, tls, ServerConfig};
+use tokio::net::TcpListener;
+use warp::http::Uri;
+
+/// Parse an optional setting from an environment variable.
+fn env_var<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|e| panic!("Unable to parse {name}: {e:?}")),
+    )
+}
+
+/// Parse a URL from `WEBHOOK_URLS`.
+fn parse_webhook(url: &str) -> Uri {
+    let url: Uri = url
+        .trim()
+        .parse()
+        .unwrap_or_else(|e| panic!("Unable to parse WEBHOOK_URLS: {e:?}"));
+    if !matches!(url.scheme_str(), Some("http" | "https")) {
+        panic!("WEBHOOK_URLS must be http:// or https:// URLs, got {url}");
+    }
+    url
+}
 
 #[tokio::main]
 async fn main() {
@@ -9,6 +39,15 @@ async fn main() {
         .parse()
         .expect("Unable to parse PORT");
 
+    let tls = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => Some(tls::TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }),
+        (Err(_), Err(_)) => None,
+        _ => panic!("TLS_CERT and TLS_KEY must be set together"),
+    };
+
     let config = ServerConfig {
         expiry_days: std::env::var("EXPIRY_DAYS")
             .unwrap_or_else(|_| String::from("1"))
@@ -22,7 +61,43 @@ async fn main() {
             ),
             Err(_) => None,
         },
+        base_path: std::env::var("BASE_PATH").unwrap_or_else(|_| String::from("/")),
+        connection_rate_limit: env_var("CONNECTION_RATE_LIMIT"),
+        ip_rate_limit: env_var("IP_RATE_LIMIT"),
+        max_documents: env_var("MAX_DOCUMENTS"),
+        memory_budget: env_var("MEMORY_BUDGET"),
+        evict_lru: env_var("EVICT_LRU").unwrap_or(false),
+        max_connections: env_var("MAX_CONNECTIONS"),
+        max_document_connections: env_var("MAX_DOCUMENT_CONNECTIONS"),
+        update_capacity: env_var("UPDATE_CAPACITY")
+            .unwrap_or_else(|| ServerConfig::default().update_capacity),
+        compression: env_var("COMPRESSION").unwrap_or(true),
+        ping_interval: match env_var("PING_INTERVAL") {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => ServerConfig::default().ping_interval,
+        },
+        ping_timeout: env_var("PING_TIMEOUT")
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| ServerConfig::default().ping_timeout),
+        webhooks: match std::env::var("WEBHOOK_URLS") {
+            Ok(urls) => urls.split(',').map(parse_webhook).collect(),
+            Err(_) => Vec::new(),
+        },
+        webhook_delay: env_var("WEBHOOK_DELAY")
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| ServerConfig::default().webhook_delay),
     };
 
-    warp::serve(server(config)).run(([0, 0, 0, 0], port)).await;
+    match tls {
+        Some(tls_config) => {
+            let listener = TcpListener::bind(("0.0.0.0", port))
+                .await
+                .expect("Unable to bind PORT");
+            tls::serve(server(config), listener, tls_config)
+                .expect("Unable to load TLS certificate")
+                .await;
+        }
+        None => warp::serve(server(config)).run(([0, 0, 0, 0], port)).await,
+    }
 }
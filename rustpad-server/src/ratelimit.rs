@@ -0,0 +1,128 @@
+//! Token bucket rate limiting for messages sent by clients.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// Parameters of a token bucket, which allows short bursts of messages while
+/// bounding the sustained rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Number of messages replenished per second.
+    pub per_second: f64,
+    /// Maximum number of messages that can be sent in a burst.
+    pub burst: u32,
+}
+
+/// Parses a rate limit written as `<per_second>,<burst>`, such as `20,100`.
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (per_second, burst) = s
+            .split_once(',')
+            .ok_or_else(|| anyhow!("expected rate limit as `<per_second>,<burst>`"))?;
+        Ok(Self {
+            per_second: per_second.trim().parse().context("invalid rate")?,
+            burst: burst.trim().parse().context("invalid burst")?,
+        })
+    }
+}
+
+/// A bucket of tokens, each of which allows one message.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, limit: &RateLimit) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token buckets shared by all connections from the same remote address.
+#[derive(Debug)]
+pub(crate) struct IpBuckets {
+    limit: RateLimit,
+    buckets: DashMap<IpAddr, TokenBucket>,
+}
+
+impl IpBuckets {
+    /// Construct an empty set of buckets with the given limit.
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Returns whether another message from `ip` is allowed, consuming a token
+    /// if so.
+    pub fn try_take(&self, ip: IpAddr) -> bool {
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(&self.limit))
+            .try_take(&self.limit)
+    }
+
+    /// Forget buckets that have not been used within the given duration.
+    pub fn prune(&self, idle: Duration) {
+        self.buckets
+            .retain(|_, bucket| bucket.updated.elapsed() < idle);
+    }
+}
+
+/// Rate limiter for the messages of a single connection.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    connection: Option<(RateLimit, TokenBucket)>,
+    ip: Option<(Arc<IpBuckets>, IpAddr)>,
+}
+
+impl Throttle {
+    /// Construct a throttle from optional per-connection and per-address limits.
+    pub fn new(connection: Option<RateLimit>, ip: Option<(Arc<IpBuckets>, IpAddr)>) -> Self {
+        Self {
+            connection: connection.map(|limit| (limit, TokenBucket::new(&limit))),
+            ip,
+        }
+    }
+
+    /// Returns whether another message is allowed, consuming a token if so.
+    pub fn allow(&mut self) -> bool {
+        if let Some((limit, bucket)) = &mut self.connection {
+            if !bucket.try_take(limit) {
+                return false;
+            }
+        }
+        if let Some((buckets, ip)) = &self.ip {
+            if !buckets.try_take(*ip) {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -1,43 +1,173 @@
 //! Eventually consistent server-side logic for Rustpad.
+//!
+//! Each document is owned by a single task, which applies messages from every
+//! connection in order and fans out updates to a bounded queue per connection.
+//! Connections that fall so far behind that their queue fills up have updates
+//! skipped, then catch up in one batch once they have drained it.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use futures::prelude::*;
 use log::{info, warn};
-use operational_transform::OperationSeq;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use operational_transform::{Operation, OperationSeq};
+use rustpad_wasm::OpSeq;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, Notify};
+use tokio::sync::{mpsc, mpsc::error::TrySendError, oneshot, Notify};
+use tokio::task;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use warp::sse;
 use warp::ws::{Message, WebSocket};
 
-use crate::{database::PersistedDocument, ot::transform_index};
+use crate::database::PersistedDocument;
+use crate::encoding::{self, Encoding};
+use crate::language;
+use crate::limits::MemoryUsage;
+use crate::{ot::transform_index, ratelimit::Throttle};
 
 /// The main object representing a collaborative session.
 pub struct Rustpad {
-    /// State modified by critical sections of the code.
-    state: RwLock<State>,
+    /// Sends commands to the task that owns the document state, other than
+    /// those from connections.
+    commands: mpsc::UnboundedSender<Command>,
+    /// Sends commands from connections to the document task, which wait for
+    /// room in the queue so that fast clients cannot grow it without bound.
+    messages: mpsc::Sender<Command>,
+    /// Counters and flags readable without waiting on the document task.
+    shared: Arc<Shared>,
+    /// Number of updates buffered for each connection.
+    update_capacity: usize,
+    /// Random tag that distinguishes revisions of this object from those of
+    /// the same document after it is loaded again.
+    instance: u64,
+}
+
+/// Summary of the document state, kept up to date by the document task.
+#[derive(Default)]
+struct Shared {
     /// Incremented to obtain unique user IDs.
     count: AtomicU64,
-    /// Used to notify clients of new text operations.
-    notify: Notify,
-    /// Used to inform all clients of metadata updates.
-    update: broadcast::Sender<ServerMsg>,
+    /// Number of operations in the history.
+    revision: AtomicUsize,
+    /// Approximate number of bytes used by the text and history.
+    memory_usage: AtomicUsize,
+    /// Whether the document has been edited or assigned a language.
+    touched: AtomicBool,
     /// Set to true when the document is destroyed.
     killed: AtomicBool,
+    /// Notified when the revision changes or the document is destroyed.
+    edited: Notify,
+    /// Notified when the document is first touched or destroyed.
+    touch: Notify,
+    /// Notified when the document is destroyed.
+    closed: Notify,
 }
 
-/// Shared state involving multiple users, protected by a lock.
+/// Requests handled by the document task, in the order they are sent.
+enum Command {
+    /// Registers a connection with the queue that its updates are sent to.
+    Join {
+        id: u64,
+        encoding: Encoding,
+        snapshot: bool,
+        queue: mpsc::Sender<Batch>,
+        resync: Arc<Notify>,
+    },
+    /// Applies a message received from a connection.
+    Message { id: u64, msg: ClientMsg },
+    /// Catches up a lagging connection after it has drained its queue.
+    Resync { id: u64 },
+    /// Removes a connection, replying once all of its messages are applied.
+    Leave { id: u64, done: oneshot::Sender<()> },
+    /// Applies an edit made without a connection, replying with the new
+    /// revision.
+    Edit {
+        id: u64,
+        revision: usize,
+        operation: OperationSeq,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    /// Applies the difference from the text at a revision to a new text,
+    /// unless there is none, and sets the language if given. Replies with the
+    /// new revision.
+    Replace {
+        id: u64,
+        revision: usize,
+        operation: OperationSeq,
+        language: Option<String>,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    /// Replies with the current text and the operations since a revision.
+    Changes {
+        since: usize,
+        reply: oneshot::Sender<(Vec<OperationSeq>, String)>,
+    },
+    /// Replies with the current revision and a snapshot of the document.
+    Snapshot(oneshot::Sender<(usize, PersistedDocument)>),
+    /// Replies with the operations up to an earlier revision, and a snapshot
+    /// of the document without its text, which is replayed by the caller.
+    SnapshotAt {
+        revision: usize,
+        reply: oneshot::Sender<Result<(Vec<OperationSeq>, PersistedDocument)>>,
+    },
+    /// Drops all current connections and refuses new ones.
+    Kill,
+}
+
+/// Maximum length of the text of a document, in characters.
+const MAX_TEXT_LENGTH: usize = 256 * 1024;
+
+/// Maximum number of commands applied before broadcasting their edits.
+const COMMAND_BATCH_SIZE: usize = 64;
+
+/// Number of commands from connections queued for the document task before
+/// connections stop reading from their sockets.
+const MESSAGE_QUEUE_CAPACITY: usize = 256;
+
+/// Minimum length in bytes of a text before its language is detected.
+const MIN_DETECT_LENGTH: usize = 64;
+
+/// Factor by which a text must grow before detecting its language is tried
+/// again, if it was not detected before.
+const DETECT_GROWTH: usize = 2;
+
+/// Messages encoded once and shared between all connections receiving them.
+type Batch = Arc<[Message]>;
+
+/// Document state, owned by the document task.
 #[derive(Default)]
 struct State {
     operations: Vec<UserOperation>,
+    /// Approximate number of bytes used by `operations`.
+    history_size: usize,
     text: String,
     language: Option<String>,
+    /// ID of the document that this document was forked from, if any.
+    parent: Option<String>,
     users: HashMap<u64, UserInfo>,
     cursors: HashMap<u64, CursorData>,
 }
 
+/// The document task's view of a single connection.
+struct Connection {
+    id: u64,
+    encoding: Encoding,
+    queue: mpsc::Sender<Batch>,
+    /// Notifies the connection that updates are being skipped, so that it
+    /// requests to catch up once its queue is drained.
+    resync: Arc<Notify>,
+    /// Whether updates are being skipped until the connection catches up.
+    lagged: bool,
+    /// Number of operations sent to the client.
+    revision: usize,
+    /// Users that the client has been told about, for recovering from lag.
+    known_users: HashSet<u64>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct UserOperation {
     id: u64,
@@ -75,8 +205,14 @@ enum ClientMsg {
 /// A message sent to the client over WebSocket.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 enum ServerMsg {
+    /// Sent first to clients that give their protocol version when
+    /// connecting, with the optional features in effect for the connection.
+    Hello { version: u32, features: Vec<String> },
     /// Informs the client of their unique socket ID.
     Identity(u64),
+    /// Sends the text at a revision to a new client in place of the history
+    /// up to that revision.
+    Snapshot { revision: usize, text: String },
     /// Broadcasts text operations to all clients.
     History {
         start: usize,
@@ -88,122 +224,414 @@ enum ServerMsg {
     UserInfo { id: u64, info: Option<UserInfo> },
     /// Broadcasts a user's cursor position.
     UserCursor { id: u64, data: CursorData },
+    /// Informs the client that its last message was rejected.
+    Error(String),
 }
 
-impl From<ServerMsg> for Message {
-    fn from(msg: ServerMsg) -> Self {
-        let serialized = serde_json::to_string(&msg).expect("failed serialize");
-        Message::text(serialized)
-    }
+/// Borrowed form of [`ServerMsg::History`], which serializes identically.
+#[derive(Serialize)]
+enum HistoryRef<'a> {
+    History {
+        start: usize,
+        operations: &'a [UserOperation],
+    },
+}
+
+/// Version of the WebSocket protocol spoken by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// WebSocket close code sent to clients with an unsupported protocol version,
+/// which should not reconnect until they are reloaded.
+pub const CLOSE_UNSUPPORTED_VERSION: u16 = 4000;
+
+/// Settings for a single WebSocket connection.
+pub struct ConnectionOptions {
+    /// Protocol version of the client, if it gave one.
+    pub version: Option<u32>,
+    /// Encoding of messages sent to the client.
+    pub encoding: Encoding,
+    /// Whether to send the current text instead of the full history.
+    pub snapshot: bool,
+    /// Rate limit on messages from the client.
+    pub throttle: Throttle,
+    /// Pings sent to detect dead connections, if enabled.
+    pub heartbeat: Option<Heartbeat>,
 }
 
+/// Schedule of WebSocket pings sent to each client.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    /// Time between pings.
+    pub interval: Duration,
+    /// Time without receiving anything from the client, including replies to
+    /// pings, after which the connection is closed.
+    pub timeout: Duration,
+}
+
+/// Default number of updates buffered for each connection.
+pub const DEFAULT_UPDATE_CAPACITY: usize = 16;
+
 impl Default for Rustpad {
     fn default() -> Self {
-        let (tx, _) = broadcast::channel(16);
-        Self {
-            state: Default::default(),
-            count: Default::default(),
-            notify: Default::default(),
-            update: tx,
-            killed: AtomicBool::new(false),
-        }
+        Self::new(DEFAULT_UPDATE_CAPACITY, None, Default::default())
     }
 }
 
-impl From<PersistedDocument> for Rustpad {
-    fn from(document: PersistedDocument) -> Self {
-        let mut operation = OperationSeq::default();
-        operation.insert(&document.text);
-
-        let rustpad = Self::default();
-        {
-            let mut state = rustpad.state.write();
-            state.text = document.text;
-            state.language = document.language;
-            state.operations.push(UserOperation {
-                id: u64::MAX,
-                operation,
-            })
+impl Rustpad {
+    /// Construct a document, optionally from persisted storage, buffering up
+    /// to `update_capacity` updates for each connection before it is
+    /// considered to be lagging. Its memory usage is added to `memory` until
+    /// the document task stops.
+    ///
+    /// This spawns the document task, so it must be called within a Tokio
+    /// runtime. The task stops once the returned object is dropped.
+    pub fn new(
+        update_capacity: usize,
+        document: Option<PersistedDocument>,
+        memory: Arc<MemoryUsage>,
+    ) -> Self {
+        let mut state = State::default();
+        if let Some(document) = document {
+            state.load(document);
+        }
+        let task = DocumentTask {
+            broadcast_revision: state.operations.len(),
+            history: HistoryCache::default(),
+            state,
+            connections: HashMap::new(),
+            shared: Default::default(),
+            detect_length: 0,
+            memory,
+        };
+        task.update_shared();
+        let shared = Arc::clone(&task.shared);
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (messages, messages_rx) = mpsc::channel(MESSAGE_QUEUE_CAPACITY);
+        tokio::spawn(task.run(commands_rx, messages_rx));
+        Self {
+            commands,
+            messages,
+            shared,
+            update_capacity: update_capacity.max(1),
+            instance: rand::random(),
         }
-        rustpad
     }
-}
 
-impl Rustpad {
     /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket) {
-        let id = self.count.fetch_add(1, Ordering::Relaxed);
+    pub async fn on_connection(&self, socket: WebSocket, options: ConnectionOptions) {
+        let id = self.shared.count.fetch_add(1, Ordering::Relaxed);
         info!("connection id={id}");
-        if let Err(e) = self.handle_connection(id, socket).await {
+        if let Err(e) = self.handle_connection(id, socket, options).await {
             warn!("connection terminated early: {}", e);
         }
         info!("disconnection, id = {}", id);
-        self.state.write().users.remove(&id);
-        self.state.write().cursors.remove(&id);
-        self.update
-            .send(ServerMsg::UserInfo { id, info: None })
-            .ok();
+        let (done, rx) = oneshot::channel();
+        self.send_message(Command::Leave { id, done }).await;
+        rx.await.ok();
+    }
+
+    /// Handle a read-only follower, sending it updates to the text, language
+    /// and users as server-sent events until `events` is closed.
+    pub async fn on_follower(&self, events: mpsc::Sender<sse::Event>, snapshot: bool) {
+        let id = self.shared.count.fetch_add(1, Ordering::Relaxed);
+        info!("follower id={id}");
+        if let Err(e) = self.handle_follower(id, &events, snapshot).await {
+            warn!("follower terminated early: {}", e);
+        }
+        info!("follower left, id = {}", id);
+        let (done, rx) = oneshot::channel();
+        self.send_message(Command::Leave { id, done }).await;
+        rx.await.ok();
     }
 
     /// Returns a snapshot of the latest text.
-    pub fn text(&self) -> String {
-        let state = self.state.read();
-        state.text.clone()
+    pub async fn text(&self) -> String {
+        self.snapshot().await.text
+    }
+
+    /// Apply an edit made at `revision` without a connection, such as from the
+    /// HTTP API, and notify connected clients. Returns the new revision.
+    pub async fn apply_edit(&self, revision: usize, operation: OperationSeq) -> Result<usize> {
+        // Edits are attributed to a fresh user ID, so that no client mistakes
+        // them for an acknowledgement of its own edit.
+        let id = self.shared.count.fetch_add(1, Ordering::Relaxed);
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Edit {
+            id,
+            revision,
+            operation,
+            reply,
+        });
+        rx.await.map_err(|_| anyhow!("document was closed"))?
+    }
+
+    /// Replace the text with `text`, editing only the parts that differ, and
+    /// set the language if given. Edits made concurrently are merged like
+    /// those of clients. Notifies connected clients and returns the new
+    /// revision.
+    pub async fn replace_text(&self, text: String, language: Option<String>) -> Result<usize> {
+        let length = text.chars().count();
+        if length > MAX_TEXT_LENGTH {
+            bail!("target length {} is greater than 256 KiB maximum", length);
+        }
+        // Diffing takes time for long texts, so it is done on a blocking
+        // thread against a snapshot rather than in the document task.
+        let (revision, document) = self.revision_snapshot().await;
+        let operation =
+            task::spawn_blocking(move || OpSeq::from_diff(&document.text, &text)).await?;
+        let id = self.shared.count.fetch_add(1, Ordering::Relaxed);
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Replace {
+            id,
+            revision,
+            operation: operation.into(),
+            language,
+            reply,
+        });
+        rx.await.map_err(|_| anyhow!("document was closed"))?
+    }
+
+    /// Returns the current revision, the text at that revision, and one
+    /// operation combining all edits made since `revision`.
+    pub async fn changes_since(&self, revision: usize) -> Result<(usize, String, OperationSeq)> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Changes {
+            since: revision,
+            reply: tx,
+        });
+        let (operations, text) = rx.await.expect("document task stopped");
+        let revision = revision + operations.len();
+        let mut operations = operations.into_iter();
+        let mut composed = operations.next().unwrap_or_default();
+        for operation in operations {
+            composed = composed.compose(&operation)?;
+        }
+        Ok((revision, text, composed))
+    }
+
+    /// Wait until the revision changes or the document is killed. Changes made
+    /// while not waiting are reported by the next call, so only one task
+    /// should wait at a time.
+    pub async fn edited(&self) {
+        self.shared.edited.notified().await
     }
 
     /// Returns a snapshot of the current document for persistence.
-    pub fn snapshot(&self) -> PersistedDocument {
-        let state = self.state.read();
-        PersistedDocument {
-            text: state.text.clone(),
-            language: state.language.clone(),
+    pub async fn snapshot(&self) -> PersistedDocument {
+        self.revision_snapshot().await.1
+    }
+
+    /// Returns the current revision and a snapshot of the document at that
+    /// revision.
+    pub async fn revision_snapshot(&self) -> (usize, PersistedDocument) {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Snapshot(tx));
+        rx.await.expect("document task stopped")
+    }
+
+    /// Returns a snapshot of the document with the text at an earlier
+    /// `revision`, and the current language.
+    ///
+    /// The text is found by replaying the history up to `revision` on a
+    /// blocking thread, so that the document task is not held up by it.
+    pub async fn snapshot_at(&self, revision: usize) -> Result<PersistedDocument> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::SnapshotAt {
+            revision,
+            reply: tx,
+        });
+        let (operations, document) = rx.await.expect("document task stopped")?;
+        task::spawn_blocking(move || {
+            let mut text = String::new();
+            for operation in operations {
+                text = operation.apply(&text)?;
+            }
+            Ok(PersistedDocument { text, ..document })
+        })
+        .await?
+    }
+
+    /// Returns a tag identifying the text at `revision` and the file type of
+    /// `language`, which differs from the tags of any other text or file type
+    /// of this document.
+    pub fn etag(&self, revision: usize, language: Option<&str>) -> String {
+        let (extension, _) = language::file_type(language);
+        format!("\"{:x}-{}-{}\"", self.instance, revision, extension)
+    }
+
+    /// Returns if this document has never been edited or assigned a language.
+    pub fn untouched(&self) -> bool {
+        !self.shared.touched.load(Ordering::Relaxed)
+    }
+
+    /// Wait until this document is edited or assigned a language, returning
+    /// false if it is destroyed first.
+    pub async fn touched(&self) -> bool {
+        loop {
+            if self.killed() {
+                return false;
+            }
+            if !self.untouched() {
+                return true;
+            }
+            self.shared.touch.notified().await;
         }
     }
 
+    /// Returns the approximate memory usage of the text and history in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.shared.memory_usage.load(Ordering::Relaxed)
+    }
+
     /// Returns the current revision.
     pub fn revision(&self) -> usize {
-        let state = self.state.read();
-        state.operations.len()
+        self.shared.revision.load(Ordering::Relaxed)
     }
 
     /// Kill this object immediately, dropping all current connections.
     pub fn kill(&self) {
-        self.killed.store(true, Ordering::Relaxed);
-        self.notify.notify_waiters();
+        self.shared.killed.store(true, Ordering::Relaxed);
+        self.shared.edited.notify_one();
+        self.shared.touch.notify_one();
+        self.shared.closed.notify_one();
+        self.send(Command::Kill);
+    }
+
+    /// Wait until this Rustpad object is killed.
+    pub async fn closed(&self) {
+        while !self.killed() {
+            self.shared.closed.notified().await;
+        }
     }
 
     /// Returns if this Rustpad object has been killed.
     pub fn killed(&self) -> bool {
-        self.killed.load(Ordering::Relaxed)
+        self.shared.killed.load(Ordering::Relaxed)
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
-        let mut update_rx = self.update.subscribe();
+    fn send(&self, command: Command) {
+        // The document task outlives `self`, so this only fails if it panicked.
+        self.commands.send(command).ok();
+    }
 
-        let mut revision: usize = self.send_initial(id, &mut socket).await?;
+    /// Send a command from a connection, waiting while the queue is full.
+    async fn send_message(&self, command: Command) {
+        self.messages.send(command).await.ok();
+    }
 
-        loop {
-            // In order to avoid the "lost wakeup" problem, we first request a
-            // notification, **then** check the current state for new revisions.
-            // This is the same approach that `tokio::sync::watch` takes.
-            let notified = self.notify.notified();
-            if self.killed() {
-                break;
+    /// Returns the number of commands from connections waiting to be applied.
+    pub fn queued_messages(&self) -> usize {
+        MESSAGE_QUEUE_CAPACITY - self.messages.capacity()
+    }
+
+    async fn handle_connection(
+        &self,
+        id: u64,
+        mut socket: WebSocket,
+        options: ConnectionOptions,
+    ) -> Result<()> {
+        let ConnectionOptions {
+            version,
+            encoding,
+            snapshot,
+            mut throttle,
+            heartbeat,
+        } = options;
+        // A client that stops reading would otherwise stall writes forever,
+        // since pings are not sent while a write is pending.
+        let write_timeout = heartbeat.map(|heartbeat| heartbeat.timeout);
+
+        if let Some(version) = version {
+            // Check the version before sending any state that the client may
+            // not understand.
+            if version != PROTOCOL_VERSION {
+                let error = ServerMsg::Error(format!(
+                    "unsupported protocol version {version}, server speaks version \
+                     {PROTOCOL_VERSION}; reload the page to update the client"
+                ));
+                let close = Message::close_with(CLOSE_UNSUPPORTED_VERSION, "unsupported version");
+                let write = async {
+                    socket.feed(encoding.encode(&error)).await?;
+                    socket.send(close).await
+                };
+                with_timeout(write_timeout, write.err_into()).await?;
+                bail!("unsupported protocol version {version}");
             }
-            if self.revision() > revision {
-                revision = self.send_history(revision, &mut socket).await?
+            let mut features = Vec::new();
+            if encoding != Encoding::Json {
+                features.push(encoding.name().into());
             }
+            if snapshot {
+                features.push("snapshot".into());
+            }
+            let hello = ServerMsg::Hello { version, features };
+            with_timeout(
+                write_timeout,
+                socket.send(encoding.encode(&hello)).err_into(),
+            )
+            .await?;
+        }
 
+        let (queue, mut updates) = mpsc::channel::<Batch>(self.update_capacity);
+        let resync = Arc::new(Notify::new());
+        self.send_message(Command::Join {
+            id,
+            encoding,
+            snapshot,
+            queue,
+            resync: Arc::clone(&resync),
+        })
+        .await;
+
+        let mut pings = heartbeat.map(|heartbeat| {
+            let mut pings =
+                time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+            pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            pings
+        });
+        let mut last_received = Instant::now();
+
+        loop {
             tokio::select! {
-                _ = notified => {}
-                update = update_rx.recv() => {
-                    socket.send(update?.into()).await?;
+                batch = updates.recv() => match batch {
+                    Some(batch) => {
+                        let write = forward(&mut socket, Some(batch), &mut updates);
+                        with_timeout(write_timeout, write).await?;
+                    }
+                    // The document task closes the queue when the document is
+                    // killed or the client sends an invalid edit.
+                    None => break,
+                },
+                _ = resync.notified() => {
+                    // Forward the updates queued before any were skipped, then
+                    // ask to be sent everything that was missed.
+                    let write = forward(&mut socket, None, &mut updates);
+                    with_timeout(write_timeout, write).await?;
+                    self.send_message(Command::Resync { id }).await;
+                }
+                _ = next_ping(&mut pings) => {
+                    let timeout = heartbeat.map(|heartbeat| heartbeat.timeout).unwrap_or_default();
+                    if last_received.elapsed() >= timeout {
+                        bail!("no response from client in {:?}", timeout);
+                    }
+                    let write = socket.send(Message::ping(Vec::new()));
+                    with_timeout(write_timeout, write.err_into()).await?;
                 }
                 result = socket.next() => {
                     match result {
                         None => break,
                         Some(message) => {
-                            self.handle_message(id, message?).await?;
+                            let message = message?;
+                            last_received = Instant::now();
+                            self.handle_message(
+                                id,
+                                message,
+                                encoding,
+                                &mut throttle,
+                                &mut socket,
+                                write_timeout,
+                            )
+                            .await?;
                         }
                     }
                 }
@@ -213,94 +641,613 @@ impl Rustpad {
         Ok(())
     }
 
-    async fn send_initial(&self, id: u64, socket: &mut WebSocket) -> Result<usize> {
-        socket.send(ServerMsg::Identity(id).into()).await?;
-        let mut messages = Vec::new();
-        let revision = {
-            let state = self.state.read();
-            if !state.operations.is_empty() {
-                messages.push(ServerMsg::History {
-                    start: 0,
-                    operations: state.operations.clone(),
-                });
+    async fn handle_follower(
+        &self,
+        id: u64,
+        events: &mpsc::Sender<sse::Event>,
+        snapshot: bool,
+    ) -> Result<()> {
+        let (queue, mut updates) = mpsc::channel::<Batch>(self.update_capacity);
+        let resync = Arc::new(Notify::new());
+        self.send_message(Command::Join {
+            id,
+            encoding: Encoding::Events,
+            snapshot,
+            queue,
+            resync: Arc::clone(&resync),
+        })
+        .await;
+
+        loop {
+            tokio::select! {
+                batch = updates.recv() => match batch {
+                    Some(batch) => forward_events(events, Some(batch), &mut updates).await?,
+                    None => break,
+                },
+                _ = resync.notified() => {
+                    forward_events(events, None, &mut updates).await?;
+                    self.send_message(Command::Resync { id }).await;
+                }
+                _ = events.closed() => break,
             }
-            if let Some(language) = &state.language {
-                messages.push(ServerMsg::Language(language.clone()));
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        id: u64,
+        message: Message,
+        encoding: Encoding,
+        throttle: &mut Throttle,
+        socket: &mut WebSocket,
+        write_timeout: Option<Duration>,
+    ) -> Result<()> {
+        let Some(msg): Option<ClientMsg> = encoding::decode(&message)? else {
+            return Ok(()); // Ignore control messages
+        };
+        if !throttle.allow() {
+            let error = ServerMsg::Error("rate limit exceeded, message dropped".into());
+            with_timeout(
+                write_timeout,
+                socket.send(encoding.encode(&error)).err_into(),
+            )
+            .await?;
+            if let ClientMsg::Edit { .. } = msg {
+                // Dropping an edit would desynchronize the client, so we close
+                // the connection instead and let it resend after reconnecting.
+                bail!("edit rate limit exceeded");
             }
-            for (&id, info) in &state.users {
-                messages.push(ServerMsg::UserInfo {
-                    id,
-                    info: Some(info.clone()),
-                });
+            return Ok(());
+        }
+        self.send_message(Command::Message { id, msg }).await;
+        Ok(())
+    }
+}
+
+/// Wait for the next ping to be due, or forever if pings are disabled.
+async fn next_ping(pings: &mut Option<Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Run a write to a socket, failing if it does not finish within `timeout`.
+async fn with_timeout(
+    timeout: Option<Duration>,
+    write: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, write)
+            .await
+            .map_err(|_| anyhow!("write to client timed out after {timeout:?}"))?,
+        None => write.await,
+    }
+}
+
+/// Write a batch and all others already queued to the socket, flushing once.
+async fn forward(
+    socket: &mut WebSocket,
+    first: Option<Batch>,
+    updates: &mut mpsc::Receiver<Batch>,
+) -> Result<()> {
+    let queued = iter::from_fn(|| updates.try_recv().ok());
+    for batch in first.into_iter().chain(queued) {
+        for msg in batch.iter() {
+            socket.feed(msg.clone()).await?;
+        }
+    }
+    socket.flush().await?;
+    Ok(())
+}
+
+/// Send a batch and all others already queued as server-sent events.
+async fn forward_events(
+    events: &mpsc::Sender<sse::Event>,
+    first: Option<Batch>,
+    updates: &mut mpsc::Receiver<Batch>,
+) -> Result<()> {
+    let queued = iter::from_fn(|| updates.try_recv().ok());
+    for batch in first.into_iter().chain(queued) {
+        for event in batch.iter().filter_map(encoding::to_event) {
+            events.send(event).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The task that owns a document's state and applies commands to it.
+struct DocumentTask {
+    state: State,
+    /// Number of operations broadcast to connections that are not lagging.
+    broadcast_revision: usize,
+    history: HistoryCache,
+    connections: HashMap<u64, Connection>,
+    shared: Arc<Shared>,
+    /// Length of the text when detecting the language was last tried.
+    detect_length: usize,
+    /// Memory usage of all documents on the server.
+    memory: Arc<MemoryUsage>,
+}
+
+impl DocumentTask {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut messages: mpsc::Receiver<Command>,
+    ) {
+        let mut buffer = Vec::new();
+        let mut message_buffer = Vec::new();
+        loop {
+            // Both channels close together when the `Rustpad` is dropped.
+            let received = tokio::select! {
+                n = commands.recv_many(&mut buffer, COMMAND_BATCH_SIZE) => n,
+                n = messages.recv_many(&mut message_buffer, COMMAND_BATCH_SIZE) => n,
+            };
+            if received == 0 {
+                break;
             }
-            for (&id, data) in &state.cursors {
-                messages.push(ServerMsg::UserCursor {
-                    id,
-                    data: data.clone(),
-                });
+            for command in buffer.drain(..).chain(message_buffer.drain(..)) {
+                self.handle(command);
             }
-            state.operations.len()
-        };
-        for msg in messages {
-            socket.send(msg.into()).await?;
+            if self.broadcast_revision < self.state.operations.len() {
+                // Give concurrent edits a chance to arrive, so that they reach
+                // every connection in one message instead of several.
+                task::yield_now().await;
+                for _ in 0..COMMAND_BATCH_SIZE {
+                    match messages.try_recv().or_else(|_| commands.try_recv()) {
+                        Ok(command) => self.handle(command),
+                        Err(_) => break,
+                    }
+                }
+            }
+            self.flush_history();
+            self.detect_language();
         }
-        Ok(revision)
+        let memory_usage = self.shared.memory_usage.swap(0, Ordering::Relaxed);
+        self.memory.update(memory_usage, 0);
     }
 
-    async fn send_history(&self, start: usize, socket: &mut WebSocket) -> Result<usize> {
-        let operations = {
-            let state = self.state.read();
-            let len = state.operations.len();
-            if start < len {
-                state.operations[start..].to_owned()
-            } else {
-                Vec::new()
+    fn handle(&mut self, command: Command) {
+        // Edits are broadcast together, but must reach clients before any
+        // other message that depends on them, such as a transformed cursor.
+        if !matches!(
+            command,
+            Command::Message {
+                msg: ClientMsg::Edit { .. },
+                ..
+            } | Command::Edit { .. }
+        ) {
+            self.flush_history();
+        }
+        match command {
+            Command::Join {
+                id,
+                encoding,
+                snapshot,
+                queue,
+                resync,
+            } => {
+                if self.shared.killed.load(Ordering::Relaxed) {
+                    return; // Dropping the queue closes the connection.
+                }
+                let mut conn = Connection {
+                    id,
+                    encoding,
+                    queue,
+                    resync,
+                    lagged: false,
+                    revision: 0,
+                    known_users: HashSet::new(),
+                };
+                let mut first = vec![ServerMsg::Identity(id)];
+                if snapshot {
+                    conn.revision = self.state.operations.len();
+                    first.push(ServerMsg::Snapshot {
+                        revision: conn.revision,
+                        text: self.state.text.clone(),
+                    });
+                }
+                if conn.catch_up(&self.state, &mut self.history, &first) {
+                    self.connections.insert(id, conn);
+                }
+            }
+            Command::Message { id, msg } => {
+                // Ignore messages after the connection was closed by an invalid
+                // edit, or after the document was killed.
+                if self.connections.contains_key(&id) {
+                    self.handle_message(id, msg);
+                    self.update_shared();
+                }
+            }
+            Command::Resync { id } => {
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    if conn.lagged && !conn.catch_up(&self.state, &mut self.history, &[]) {
+                        self.connections.remove(&id);
+                    }
+                }
+            }
+            Command::Leave { id, done } => {
+                self.connections.remove(&id);
+                let had_info = self.state.users.remove(&id).is_some();
+                let had_cursor = self.state.cursors.remove(&id).is_some();
+                // Others were never told about connections without presence,
+                // such as followers.
+                if had_info || had_cursor {
+                    self.broadcast(ServerMsg::UserInfo { id, info: None });
+                }
+                done.send(()).ok();
+            }
+            Command::Edit {
+                id,
+                revision,
+                operation,
+                reply,
+            } => {
+                if self.shared.killed.load(Ordering::Relaxed) {
+                    return; // Dropping the reply reports that it was closed.
+                }
+                let result = self.state.apply_edit(id, revision, operation);
+                self.update_shared();
+                reply
+                    .send(result.map(|()| self.state.operations.len()))
+                    .ok();
+            }
+            Command::Replace {
+                id,
+                revision,
+                operation,
+                language,
+                reply,
+            } => {
+                if self.shared.killed.load(Ordering::Relaxed) {
+                    return;
+                }
+                let result = match operation.is_noop() {
+                    true => Ok(()),
+                    false => self.state.apply_edit(id, revision, operation),
+                };
+                if let (Ok(()), Some(language)) = (&result, language) {
+                    // Clients receive the new text before its language.
+                    self.flush_history();
+                    self.set_language(language);
+                }
+                self.update_shared();
+                reply
+                    .send(result.map(|()| self.state.operations.len()))
+                    .ok();
+            }
+            Command::Changes { since, reply } => {
+                let operations = self.state.operations.get(since..).unwrap_or_default();
+                let operations = operations.iter().map(|op| op.operation.clone());
+                reply
+                    .send((operations.collect(), self.state.text.clone()))
+                    .ok();
+            }
+            Command::Snapshot(reply) => {
+                let document = PersistedDocument {
+                    text: self.state.text.clone(),
+                    language: self.state.language.clone(),
+                    parent: self.state.parent.clone(),
+                };
+                reply.send((self.state.operations.len(), document)).ok();
+            }
+            Command::SnapshotAt { revision, reply } => {
+                reply.send(self.state.operations_at(revision)).ok();
+            }
+            Command::Kill => {
+                self.connections.clear();
+                self.update_shared();
             }
-        };
-        let num_ops = operations.len();
-        if num_ops > 0 {
-            let msg = ServerMsg::History { start, operations };
-            socket.send(msg.into()).await?;
         }
-        Ok(start + num_ops)
     }
 
-    async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
-        let msg: ClientMsg = match message.to_str() {
-            Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
-            Err(()) => return Ok(()), // Ignore non-text messages
-        };
+    fn handle_message(&mut self, id: u64, msg: ClientMsg) {
         match msg {
             ClientMsg::Edit {
                 revision,
                 operation,
             } => {
-                self.apply_edit(id, revision, operation)
-                    .context("invalid edit operation")?;
-                self.notify.notify_waiters();
-            }
-            ClientMsg::SetLanguage(language) => {
-                self.state.write().language = Some(language.clone());
-                self.update.send(ServerMsg::Language(language)).ok();
+                if let Err(e) = self.state.apply_edit(id, revision, operation) {
+                    warn!("closing connection {}: invalid edit operation: {}", id, e);
+                    self.connections.remove(&id);
+                }
             }
+            ClientMsg::SetLanguage(language) => match language::normalize(&language) {
+                Ok(language) => self.set_language(language.into()),
+                Err(e) => self.send_error(id, e.to_string()),
+            },
             ClientMsg::ClientInfo(info) => {
-                self.state.write().users.insert(id, info.clone());
-                let msg = ServerMsg::UserInfo {
+                self.state.users.insert(id, info.clone());
+                self.broadcast(ServerMsg::UserInfo {
                     id,
                     info: Some(info),
-                };
-                self.update.send(msg).ok();
+                });
             }
             ClientMsg::CursorData(data) => {
-                self.state.write().cursors.insert(id, data.clone());
-                let msg = ServerMsg::UserCursor { id, data };
-                self.update.send(msg).ok();
+                self.state.cursors.insert(id, data.clone());
+                self.broadcast(ServerMsg::UserCursor { id, data });
             }
         }
-        Ok(())
     }
 
-    fn apply_edit(&self, id: u64, revision: usize, mut operation: OperationSeq) -> Result<()> {
+    /// Send an error to one connection, after the updates already queued for it.
+    fn send_error(&mut self, id: u64, error: String) {
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let batch: Batch = Arc::new([conn.encoding.encode(&ServerMsg::Error(error))]);
+        let revision = conn.revision;
+        if !conn.send(&batch, revision, &[]) {
+            self.connections.remove(&id);
+        }
+    }
+
+    fn set_language(&mut self, language: String) {
+        self.state.language = Some(language.clone());
+        self.broadcast(ServerMsg::Language(language));
+    }
+
+    /// Detect the language of a document that has none from its text, once it
+    /// is long enough, and broadcast it.
+    fn detect_language(&mut self) {
+        let state = &self.state;
+        let length = state.text.len();
+        if state.language.is_some()
+            || length < MIN_DETECT_LENGTH.max(self.detect_length * DETECT_GROWTH)
+        {
+            return;
+        }
+        self.detect_length = length;
+        if let Some(language) = language::detect(None, &state.text) {
+            info!("detected language {}", language);
+            self.set_language(language.into());
+            self.update_shared();
+        }
+    }
+
+    /// Broadcast all operations applied since the last call in one message.
+    fn flush_history(&mut self) {
+        let operations = &self.state.operations;
+        let start = self.broadcast_revision;
+        let revision = operations.len();
+        if start == revision {
+            return;
+        }
+        let history = &mut self.history;
+        let mut send = |conn: &mut Connection| match history.get(operations, start, conn.encoding) {
+            Some(batch) => conn.send(&batch, revision, &[]),
+            None => true,
+        };
+        // Acknowledge edits to their authors first, since they are waiting on
+        // the acknowledgement before sending their next edit.
+        let authors: HashSet<u64> = operations[start..].iter().map(|op| op.id).collect();
+        for id in &authors {
+            if let Some(conn) = self.connections.get_mut(id) {
+                if !send(conn) {
+                    self.connections.remove(id);
+                }
+            }
+        }
+        self.connections
+            .retain(|id, conn| authors.contains(id) || send(conn));
+        self.broadcast_revision = revision;
+    }
+
+    /// Encode a message once for each encoding and queue it for every
+    /// connection.
+    fn broadcast(&mut self, msg: ServerMsg) {
+        let msgs = [msg];
+        let mut batches: [Option<Batch>; Encoding::COUNT] = Default::default();
+        let revision = self.broadcast_revision;
+        self.connections.retain(|_, conn| {
+            if !conn.receives(&msgs[0]) {
+                return true;
+            }
+            let batch = batches[conn.encoding as usize]
+                .get_or_insert_with(|| Arc::new([conn.encoding.encode(&msgs[0])]));
+            conn.send(batch, revision, &msgs)
+        });
+    }
+
+    fn update_shared(&self) {
+        let state = &self.state;
+        let shared = &self.shared;
+        let revision = state.operations.len();
+        if shared.revision.swap(revision, Ordering::Relaxed) != revision {
+            shared.edited.notify_one();
+        }
+        // Killed documents are about to be freed, so they no longer count.
+        let memory_usage = match shared.killed.load(Ordering::Relaxed) {
+            true => 0,
+            false => state.text.len() + state.history_size,
+        };
+        let old_usage = shared.memory_usage.swap(memory_usage, Ordering::Relaxed);
+        self.memory.update(old_usage, memory_usage);
+        let touched = !state.operations.is_empty() || state.language.is_some();
+        if shared.touched.swap(touched, Ordering::Relaxed) != touched {
+            shared.touch.notify_one();
+        }
+    }
+}
+
+impl Connection {
+    /// Queue a batch of messages that brings the client up to `revision` and
+    /// includes the presence updates in `msgs`, returning false if the
+    /// connection is closed.
+    ///
+    /// Batches are skipped while the connection is lagging.
+    fn send(&mut self, batch: &Batch, revision: usize, msgs: &[ServerMsg]) -> bool {
+        if self.lagged {
+            return true;
+        }
+        match self.queue.try_send(Arc::clone(batch)) {
+            Ok(()) => {
+                self.revision = revision;
+                for msg in msgs {
+                    self.track(msg);
+                }
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                warn!("connection {} lagged, skipping updates", self.id);
+                self.lagged = true;
+                self.resync.notify_one();
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Send the operations that the client is missing and the current
+    /// language, users and cursors, replacing any presence information that
+    /// the client has previously received.
+    fn catch_up(&mut self, state: &State, history: &mut HistoryCache, first: &[ServerMsg]) -> bool {
+        let encode = |msg: &ServerMsg| self.encoding.encode(msg);
+        let first = first.iter().filter(|msg| self.receives(msg));
+        let mut batch: Vec<Message> = first.map(encode).collect();
+        if let Some(msg) = history.get(&state.operations, self.revision, self.encoding) {
+            batch.push(msg[0].clone());
+        }
+        let mut msgs = Vec::new();
+        if let Some(language) = &state.language {
+            msgs.push(ServerMsg::Language(language.clone()));
+        }
+        for &id in &self.known_users {
+            if !state.users.contains_key(&id) && !state.cursors.contains_key(&id) {
+                msgs.push(ServerMsg::UserInfo { id, info: None });
+            }
+        }
+        for (&id, info) in &state.users {
+            msgs.push(ServerMsg::UserInfo {
+                id,
+                info: Some(info.clone()),
+            });
+        }
+        for (&id, data) in &state.cursors {
+            msgs.push(ServerMsg::UserCursor {
+                id,
+                data: data.clone(),
+            });
+        }
+        msgs.retain(|msg| self.receives(msg));
+        batch.extend(msgs.iter().map(encode));
+        self.lagged = false;
+        self.send(&batch.into(), state.operations.len(), &msgs)
+    }
+
+    /// Record what the client has been told about from a message sent to it.
+    /// Returns whether the client receives a message. Followers only receive
+    /// changes to the document and its users, not cursor movements or their
+    /// identity.
+    fn receives(&self, msg: &ServerMsg) -> bool {
+        self.encoding != Encoding::Events
+            || matches!(
+                msg,
+                ServerMsg::Snapshot { .. }
+                    | ServerMsg::History { .. }
+                    | ServerMsg::Language(_)
+                    | ServerMsg::UserInfo { .. }
+            )
+    }
+
+    fn track(&mut self, msg: &ServerMsg) {
+        match msg {
+            ServerMsg::UserInfo { id, info: None } => {
+                self.known_users.remove(id);
+            }
+            ServerMsg::UserInfo { id, .. } | ServerMsg::UserCursor { id, .. } => {
+                self.known_users.insert(*id);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Encoded `History` messages for the current revision, by start revision.
+///
+/// Clients that join or catch up at the same revision share one encoding of
+/// the operations they are missing.
+#[derive(Default)]
+struct HistoryCache {
+    revision: usize,
+    batches: HashMap<(usize, Encoding), Batch>,
+}
+
+impl HistoryCache {
+    /// Returns a `History` message with the operations after `start`, if any.
+    fn get(
+        &mut self,
+        operations: &[UserOperation],
+        start: usize,
+        encoding: Encoding,
+    ) -> Option<Batch> {
+        if start >= operations.len() {
+            return None;
+        }
+        if self.revision != operations.len() {
+            self.revision = operations.len();
+            self.batches.clear();
+        }
+        let batch = self.batches.entry((start, encoding)).or_insert_with(|| {
+            let msg = HistoryRef::History {
+                start,
+                operations: &operations[start..],
+            };
+            Arc::new([encoding.encode(&msg)])
+        });
+        Some(Arc::clone(batch))
+    }
+}
+
+impl State {
+    /// Initialize the contents of an empty document from persisted storage.
+    fn load(&mut self, document: PersistedDocument) {
+        let mut operation = OperationSeq::default();
+        operation.insert(&document.text);
+        self.text = document.text;
+        // Languages stored before they were validated may be unknown, and are
+        // kept as they are until a client sets another.
+        self.language = document
+            .language
+            .map(|language| match language::normalize(&language) {
+                Ok(normalized) => normalized.into(),
+                Err(e) => {
+                    warn!("keeping stored language: {}", e);
+                    language
+                }
+            });
+        self.parent = document.parent;
+        self.history_size = operation_size(&operation);
+        self.operations.push(UserOperation {
+            id: u64::MAX,
+            operation,
+        })
+    }
+
+    /// Returns the operations up to `revision`, and a snapshot of the
+    /// document without its text.
+    fn operations_at(&self, revision: usize) -> Result<(Vec<OperationSeq>, PersistedDocument)> {
+        let Some(operations) = self.operations.get(..revision) else {
+            bail!(
+                "revision {revision} is newer than the document, at revision {}",
+                self.operations.len()
+            );
+        };
+        let operations = operations.iter().map(|op| op.operation.clone()).collect();
+        let document = PersistedDocument {
+            text: String::new(),
+            language: self.language.clone(),
+            parent: self.parent.clone(),
+        };
+        Ok((operations, document))
+    }
+
+    fn apply_edit(&mut self, id: u64, revision: usize, mut operation: OperationSeq) -> Result<()> {
         info!(
             "edit: id = {}, revision = {}, base_len = {}, target_len = {}",
             id,
@@ -308,23 +1255,21 @@ impl Rustpad {
             operation.base_len(),
             operation.target_len()
         );
-        let state = self.state.upgradable_read();
-        let len = state.operations.len();
+        let len = self.operations.len();
         if revision > len {
             bail!("got revision {}, but current is {}", revision, len);
         }
-        for history_op in &state.operations[revision..] {
+        for history_op in &self.operations[revision..] {
             operation = operation.transform(&history_op.operation)?.0;
         }
-        if operation.target_len() > 256 * 1024 {
+        if operation.target_len() > MAX_TEXT_LENGTH {
             bail!(
                 "target length {} is greater than 256 KiB maximum",
                 operation.target_len()
             );
         }
-        let new_text = operation.apply(&state.text)?;
-        let mut state = RwLockUpgradableReadGuard::upgrade(state);
-        for (_, data) in state.cursors.iter_mut() {
+        let new_text = operation.apply(&self.text)?;
+        for (_, data) in self.cursors.iter_mut() {
             for cursor in data.cursors.iter_mut() {
                 *cursor = transform_index(&operation, *cursor);
             }
@@ -333,8 +1278,22 @@ impl Rustpad {
                 *end = transform_index(&operation, *end);
             }
         }
-        state.operations.push(UserOperation { id, operation });
-        state.text = new_text;
+        self.history_size += operation_size(&operation);
+        self.operations.push(UserOperation { id, operation });
+        self.text = new_text;
         Ok(())
     }
 }
+
+/// Estimate the number of bytes used to store an operation in history.
+fn operation_size(operation: &OperationSeq) -> usize {
+    let inserted: usize = operation
+        .ops()
+        .iter()
+        .map(|op| match op {
+            Operation::Insert(s) => s.len(),
+            _ => 0,
+        })
+        .sum();
+    std::mem::size_of::<UserOperation>() + std::mem::size_of_val(operation.ops()) + inserted
+}
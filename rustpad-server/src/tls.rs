@@ -0,0 +1,183 @@
+//! TLS termination, with certificates that are reloaded when they change.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use futures::prelude::*;
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use hyper::{Body, Request};
+use log::{error, info, warn};
+use parking_lot::RwLock;
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_rustls::rustls::{
+    self,
+    crypto::ring::sign::any_supported_type,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+use warp::{filters::BoxedFilter, Reply};
+
+/// Paths to the PEM-encoded certificate chain and private key.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Path to the certificate chain, leaf certificate first.
+    pub cert_path: PathBuf,
+    /// Path to the private key for the leaf certificate.
+    pub key_path: PathBuf,
+}
+
+/// Resolves the server certificate, reloading it when the files change.
+#[derive(Debug)]
+struct CertResolver {
+    config: TlsConfig,
+    current: RwLock<LoadedCert>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: (SystemTime, SystemTime),
+}
+
+impl CertResolver {
+    fn new(config: TlsConfig) -> Result<Self> {
+        let current = RwLock::new(load(&config)?);
+        Ok(Self { config, current })
+    }
+
+    /// Reload the certificate if either file has been modified since the last
+    /// successful load, returning whether a reload happened.
+    fn reload_if_changed(&self) -> Result<bool> {
+        if modified_times(&self.config)? == self.current.read().modified {
+            return Ok(false);
+        }
+        *self.current.write() = load(&self.config)?;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().key))
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Result<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| -> Result<SystemTime> {
+        fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .with_context(|| format!("failed to stat {}", path.display()))
+    };
+    Ok((modified(&config.cert_path)?, modified(&config.key_path)?))
+}
+
+fn load(config: &TlsConfig) -> Result<LoadedCert> {
+    let modified = modified_times(config)?;
+
+    let mut reader = BufReader::new(File::open(&config.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!(
+            "no certificates found in {}",
+            config.cert_path.display()
+        ));
+    }
+
+    let mut reader = BufReader::new(File::open(&config.key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("no private key found in {}", config.key_path.display()))?;
+    let key = any_supported_type(&key).context("unsupported private key type")?;
+
+    Ok(LoadedCert {
+        key: Arc::new(CertifiedKey::new(certs, key)),
+        modified,
+    })
+}
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Periodically checks the certificate files on disk for changes.
+async fn reloader(resolver: Weak<CertResolver>) {
+    loop {
+        time::sleep(RELOAD_INTERVAL).await;
+        let Some(resolver) = resolver.upgrade() else {
+            break;
+        };
+        match resolver.reload_if_changed() {
+            Ok(true) => info!("reloaded TLS certificate"),
+            Ok(false) => {}
+            Err(e) => error!("when reloading TLS certificate: {:#}", e),
+        }
+    }
+}
+
+/// Remote address of a connection accepted over TLS, attached to each of its
+/// requests as an extension, since warp only knows the addresses of the
+/// connections that it accepts itself.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Serve a filter over TLS connections accepted from a listener.
+///
+/// Handshakes are performed concurrently, and connections that fail to
+/// complete a handshake are logged and dropped. Returns an error if the
+/// certificate cannot be loaded.
+pub fn serve<R: Reply + 'static>(
+    filter: BoxedFilter<(R,)>,
+    listener: TcpListener,
+    config: TlsConfig,
+) -> Result<impl Future<Output = ()>> {
+    let resolver = Arc::new(CertResolver::new(config)?);
+    tokio::spawn(reloader(Arc::downgrade(&resolver)));
+
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let service = warp::service(filter);
+
+    Ok(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Errors such as running out of file descriptors persist
+                    // for a while, so retrying at once would spin.
+                    warn!("failed to accept connection: {}", e);
+                    time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+            tokio::spawn(async move {
+                let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return warn!("TLS handshake with {} failed: {}", peer, e),
+                    Err(_) => return warn!("TLS handshake with {} timed out", peer),
+                };
+                let service = service_fn(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(RemoteAddr(peer));
+                    service.clone().call(req)
+                });
+                let conn = Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades();
+                if let Err(e) = conn.await {
+                    warn!("connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    })
+}
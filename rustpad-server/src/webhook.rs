@@ -0,0 +1,283 @@
+//! Outgoing webhooks that notify other services when documents change.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::{future::BoxFuture, prelude::*};
+use hyper::client::connect::{Connected, Connection, HttpConnector};
+use hyper::service::Service;
+use hyper::{header::CONTENT_TYPE, Body, Client, Request, Uri};
+use log::{error, info, warn};
+use operational_transform::{Operation, OperationSeq};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tokio_rustls::rustls::{
+    pki_types::{Der, ServerName, TrustAnchor},
+    ClientConfig, RootCertStore,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::rustpad::Rustpad;
+
+/// Maximum time to wait for a webhook receiver to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of debounce delays after the first edit that a webhook is sent at
+/// the latest, even if the document is still being edited.
+const MAX_DELAY_FACTOR: u32 = 10;
+
+/// Webhook URLs and the client used to send requests to them.
+pub struct Webhooks {
+    urls: Vec<Uri>,
+    delay: Duration,
+    client: Client<HttpsConnector>,
+}
+
+/// Connects to `http` URLs over TCP and to `https` URLs over TLS, trusting the
+/// Mozilla root certificates.
+#[derive(Clone)]
+struct HttpsConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+}
+
+/// A connection to a webhook receiver, with or without TLS.
+enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Body of a webhook request.
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// ID of the document.
+    id: &'a str,
+    /// Revision of the document after the changes.
+    revision: usize,
+    /// Ranges of the new text that changed since the previous request.
+    changes: Vec<Change>,
+    /// Hex-encoded SHA-256 hash of the new text.
+    sha256: String,
+}
+
+/// A range of text that was inserted or replaced, in Unicode code points.
+/// Deleted text is reported as an empty range where it was removed.
+#[derive(Serialize)]
+struct Change {
+    start: u64,
+    end: u64,
+}
+
+impl Webhooks {
+    /// Construct webhooks that are sent to every URL in `urls` once edits
+    /// have stopped for `delay`.
+    pub fn new(urls: Vec<Uri>, delay: Duration) -> Self {
+        Self {
+            urls,
+            delay,
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    /// Send a request to every webhook URL, logging any failures.
+    async fn send(&self, payload: &Payload<'_>) {
+        let body = serde_json::to_string(payload).expect("failed serialize");
+        let body = &body;
+        future::join_all(self.urls.iter().map(|url| async move {
+            let request = Request::post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.clone()))
+                .expect("invalid webhook request");
+            match time::timeout(WEBHOOK_TIMEOUT, self.client.request(request)).await {
+                Ok(Ok(resp)) if resp.status().is_success() => {}
+                Ok(Ok(resp)) => warn!("webhook {} responded with {}", url, resp.status()),
+                Ok(Err(e)) => warn!("webhook {} failed: {}", url, e),
+                Err(_) => warn!("webhook {} timed out", url),
+            }
+        }))
+        .await;
+    }
+}
+
+impl HttpsConnector {
+    fn new() -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let mut roots = RootCertStore::empty();
+        roots.extend(
+            webpki_roots::TLS_SERVER_ROOTS
+                .0
+                .iter()
+                .map(|anchor| TrustAnchor {
+                    subject: Der::from_slice(anchor.subject),
+                    subject_public_key_info: Der::from_slice(anchor.spki),
+                    name_constraints: anchor.name_constraints.map(Der::from_slice),
+                }),
+        );
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            http,
+            tls: TlsConnector::from(Arc::new(config)),
+        }
+    }
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = MaybeTlsStream;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, Result<MaybeTlsStream>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let tls = (uri.scheme_str() == Some("https")).then(|| self.tls.clone());
+        let host = uri.host().unwrap_or_default();
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let connecting = self.http.call(uri);
+        async move {
+            let stream = connecting.await?;
+            match tls {
+                Some(tls) => {
+                    let name = ServerName::try_from(host)?;
+                    let stream = tls.connect(name, stream).await?;
+                    Ok(MaybeTlsStream::Tls(Box::new(stream)))
+                }
+                None => Ok(MaybeTlsStream::Plain(stream)),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Connection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.connected(),
+            MaybeTlsStream::Tls(stream) => stream.get_ref().0.connected(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Sends webhooks after a document is edited since `revision`, until it is
+/// killed.
+pub async fn notifier(
+    id: String,
+    rustpad: Arc<Rustpad>,
+    webhooks: Arc<Webhooks>,
+    mut revision: usize,
+) {
+    while !rustpad.killed() {
+        rustpad.edited().await;
+        // Wait for edits to settle, so that typing does not send a request
+        // for every keystroke.
+        let deadline = Instant::now() + webhooks.delay * MAX_DELAY_FACTOR;
+        while !rustpad.killed() {
+            let timeout = deadline.min(Instant::now() + webhooks.delay);
+            if time::timeout_at(timeout, rustpad.edited()).await.is_err() {
+                break;
+            }
+        }
+        if rustpad.revision() == revision {
+            continue;
+        }
+        let (new_revision, text, operation) = match rustpad.changes_since(revision).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("when computing changes to document {}: {}", id, e);
+                return;
+            }
+        };
+        info!(
+            "sending webhooks for revision {} of id = {}",
+            new_revision, id
+        );
+        let payload = Payload {
+            id: &id,
+            revision: new_revision,
+            changes: changed_ranges(&operation),
+            sha256: format!("{:x}", Sha256::digest(&text)),
+        };
+        webhooks.send(&payload).await;
+        revision = new_revision;
+    }
+}
+
+/// Returns the ranges of the new text that were changed by an operation,
+/// merging adjacent ranges.
+fn changed_ranges(operation: &OperationSeq) -> Vec<Change> {
+    let mut changes: Vec<Change> = Vec::new();
+    let mut position = 0;
+    for op in operation.ops() {
+        let start = position;
+        match op {
+            Operation::Retain(n) => {
+                position += n;
+                continue;
+            }
+            Operation::Insert(s) => position += bytecount::num_chars(s.as_bytes()) as u64,
+            Operation::Delete(_) => {}
+        }
+        match changes.last_mut() {
+            Some(last) if last.end == start => last.end = position,
+            _ => changes.push(Change {
+                start,
+                end: position,
+            }),
+        }
+    }
+    changes
+}
@@ -0,0 +1,54 @@
+//! Tests for serving routes under a configurable URL prefix.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_base_path() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        base_path: "/tools/pad/".into(),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect_path(&filter, "/tools/pad/api/socket/foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let resp = warp::test::request()
+        .path("/tools/pad/api/text/foobar")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .path("/api/text/foobar")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    assert!(connect(&filter, "foobar").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_base_path_redirect() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        base_path: "/tools/pad".into(),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .path("/tools/pad")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 308);
+    assert_eq!(resp.headers()["location"], "/tools/pad/");
+
+    Ok(())
+}
@@ -6,8 +6,9 @@ use anyhow::Result;
 use common::*;
 use operational_transform::OperationSeq;
 use rustpad_server::{server, ServerConfig};
-use serde_json::json;
+use serde_json::{json, Value};
 use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
 
 pub mod common;
 
@@ -46,7 +47,42 @@ async fn test_cleanup() -> Result<()> {
     expect_text(&filter, "old", "hello").await;
 
     time::advance(3 * hour).await;
+    tokio::task::yield_now().await;
     expect_text(&filter, "old", "").await;
 
     Ok(())
 }
+
+async fn num_documents(filter: &BoxedFilter<(impl Reply + 'static,)>) -> Result<u64> {
+    let resp = warp::test::request().path("/api/stats").reply(filter).await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    Ok(stats["num_documents"].as_u64().unwrap_or_default())
+}
+
+#[tokio::test]
+async fn test_discard_untouched() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Documents are only counted once they are edited.
+    let mut client = connect(&filter, "visited").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(num_documents(&filter).await?, 0);
+
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(num_documents(&filter).await?, 0);
+
+    let mut client = connect(&filter, "edited").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(num_documents(&filter).await?, 1);
+
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(num_documents(&filter).await?, 1);
+
+    Ok(())
+}
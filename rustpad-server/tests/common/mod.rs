@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
+use miniz_oxide::inflate::decompress_to_vec_zlib;
 use serde_json::Value;
-use warp::{filters::BoxedFilter, test::WsClient, Reply};
+use warp::{filters::BoxedFilter, test::WsClient, ws::Message, Reply};
 
 /// A test WebSocket client that sends and receives JSON messages.
 pub struct JsonSocket(WsClient);
@@ -11,11 +12,43 @@ impl JsonSocket {
     }
 
     pub async fn recv(&mut self) -> Result<Value> {
-        let msg = self.0.recv().await?;
+        let msg = self.next().await?;
         let msg = msg.to_str().map_err(|_| anyhow!("non-string message"))?;
         Ok(serde_json::from_str(msg)?)
     }
 
+    pub async fn send_msgpack(&mut self, msg: &Value) {
+        let bytes = rmp_serde::to_vec_named(msg).expect("failed serialize");
+        self.0.send(Message::binary(bytes)).await
+    }
+
+    pub async fn recv_msgpack(&mut self) -> Result<Value> {
+        let msg = self.next().await?;
+        if !msg.is_binary() {
+            return Err(anyhow!("non-binary message"));
+        }
+        Ok(rmp_serde::from_slice(msg.as_bytes())?)
+    }
+
+    pub async fn recv_deflate(&mut self) -> Result<Value> {
+        let msg = self.next().await?;
+        if !msg.is_binary() {
+            return Err(anyhow!("non-binary message"));
+        }
+        let json = decompress_to_vec_zlib(msg.as_bytes()).map_err(|e| anyhow!("{e:?}"))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Receive the next data message, skipping pings from the server.
+    async fn next(&mut self) -> Result<Message> {
+        loop {
+            let msg = self.0.recv().await?;
+            if !msg.is_ping() && !msg.is_pong() {
+                return Ok(msg);
+            }
+        }
+    }
+
     pub async fn recv_closed(&mut self) -> Result<()> {
         self.0.recv_closed().await.map_err(|e| e.into())
     }
@@ -25,9 +58,17 @@ impl JsonSocket {
 pub async fn connect(
     filter: &BoxedFilter<(impl Reply + 'static,)>,
     id: &str,
+) -> Result<JsonSocket> {
+    connect_path(filter, &format!("/api/socket/{}", id)).await
+}
+
+/// Connect a new test client WebSocket at an arbitrary path.
+pub async fn connect_path(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    path: &str,
 ) -> Result<JsonSocket> {
     let client = warp::test::ws()
-        .path(&format!("/api/socket/{}", id))
+        .path(path)
         .handshake(filter.clone())
         .await?;
     Ok(JsonSocket(client))
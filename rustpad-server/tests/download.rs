@@ -0,0 +1,134 @@
+//! Tests for downloading documents as files.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    database::{Database, PersistedDocument},
+    server, ServerConfig,
+};
+use serde_json::json;
+use tempfile::NamedTempFile;
+use warp::{filters::BoxedFilter, http::Response, hyper::body::Bytes, Reply};
+
+pub mod common;
+
+async fn download(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    if_none_match: Option<&str>,
+) -> Response<Bytes> {
+    let mut request = warp::test::request().path(&format!("/api/download/{id}"));
+    if let Some(tag) = if_none_match {
+        request = request.header("if-none-match", tag);
+    }
+    request.reply(filter).await
+}
+
+#[tokio::test]
+async fn test_download() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["fn main() {}\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let resp = download(&filter, "foobar", None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "fn main() {}\n");
+    let headers = resp.headers();
+    assert_eq!(headers["content-type"], "text/rust; charset=utf-8");
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"foobar.rs\""
+    );
+    let etag = headers["etag"].to_str()?.to_owned();
+
+    // Unchanged documents are not sent again.
+    let resp = download(&filter, "foobar", Some(&etag)).await;
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    assert!(resp.body().is_empty());
+    let weak = format!("\"other\", W/{etag}");
+    let resp = download(&filter, "foobar", Some(&weak)).await;
+    assert_eq!(resp.status(), 304);
+
+    // Changing the language changes the file type without a new revision.
+    client.send(&json!({ "SetLanguage": "plaintext" })).await;
+    client.recv().await?;
+    let resp = download(&filter, "foobar", Some(&etag)).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/plain; charset=utf-8");
+    let plain_etag = resp.headers()["etag"].to_str()?.to_owned();
+    assert_ne!(plain_etag, etag);
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    let msg = json!({ "Edit": { "revision": 1, "operation": [13, "// done\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let resp = download(&filter, "foobar", Some(&etag)).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "fn main() {}\n// done\n");
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_plain() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = download(&filter, "notes.v2", None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "");
+    let headers = resp.headers();
+    assert_eq!(headers["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"notes_v2.txt\""
+    );
+    assert!(!headers.contains_key("etag"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let path = NamedTempFile::new()?.into_temp_path();
+    let uri = format!("sqlite://{}", path.to_str().expect("invalid path"));
+    let database = Database::new(&uri).await?;
+    let document = PersistedDocument {
+        text: "print(1)\n".into(),
+        language: Some("python".into()),
+        parent: None,
+    };
+    database.store("stored", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+
+    // Documents that are only in the database are tagged by their contents.
+    let resp = download(&filter, "stored", None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "print(1)\n");
+    let etag = resp.headers()["etag"].to_str()?.to_owned();
+    let resp = download(&filter, "stored", Some(&etag)).await;
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+
+    let document = PersistedDocument {
+        language: Some("plaintext".into()),
+        ..document
+    };
+    database.store("stored", &document).await?;
+    let resp = download(&filter, "stored", Some(&etag)).await;
+    assert_eq!(resp.status(), 200);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+    Ok(())
+}
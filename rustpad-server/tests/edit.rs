@@ -0,0 +1,184 @@
+//! Tests for submitting edits over HTTP.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Post an edit request, returning the status and response body.
+async fn post_edit(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    body: &Value,
+) -> (u16, String) {
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/edit/{}", id))
+        .json(body)
+        .reply(filter)
+        .await;
+    let body = String::from_utf8_lossy(resp.body()).into_owned();
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_edit_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let (status, body) = post_edit(&filter, "foobar", &json!({ "text": "hello" })).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 1 })
+    );
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [{ "id": 1, "operation": ["hello"] }]
+            }
+        })
+    );
+
+    // Only the difference is sent, so that other text keeps its position.
+    let body = json!({ "text": "hello, world" });
+    let (status, body) = post_edit(&filter, "foobar", &body).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 2 })
+    );
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [{ "id": 2, "operation": [5, ", world"] }]
+            }
+        })
+    );
+
+    // Replacing the text with itself does not create a new revision.
+    let body = json!({ "text": "hello, world" });
+    let (status, body) = post_edit(&filter, "foobar", &body).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 2 })
+    );
+
+    expect_text(&filter, "foobar", "hello, world").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edit_operation() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let body = json!({ "revision": 0, "operation": ["build started\n"] });
+    let (status, body) = post_edit(&filter, "ci", &body).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 1 })
+    );
+
+    let mut client = connect(&filter, "ci").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv().await?;
+    client
+        .send(&json!({ "Edit": { "revision": 1, "operation": ["# ", 14] } }))
+        .await;
+    client.recv().await?;
+
+    // Operations at an older revision are transformed against later edits.
+    let body = json!({ "revision": 1, "operation": [14, "build passed\n"] });
+    let (status, body) = post_edit(&filter, "ci", &body).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 3 })
+    );
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [{ "id": 2, "operation": [16, "build passed\n"] }]
+            }
+        })
+    );
+    expect_text(&filter, "ci", "# build started\nbuild passed\n").await;
+
+    let body = json!({ "revision": 3, "operation": [100, "x"] });
+    let (status, _) = post_edit(&filter, "ci", &body).await;
+    assert_eq!(status, 400);
+    let body = json!({ "revision": 4, "operation": [] });
+    let (status, _) = post_edit(&filter, "ci", &body).await;
+    assert_eq!(status, 400);
+    let (status, _) = post_edit(&filter, "ci", &json!({ "lines": [] })).await;
+    assert_eq!(status, 400);
+    expect_text(&filter, "ci", "# build started\nbuild passed\n").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edit_invalid_new_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let body = json!({ "revision": 0, "operation": [5] });
+    let (status, _) = post_edit(&filter, "empty", &body).await;
+    assert_eq!(status, 400);
+
+    let stats = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(stats.body())?;
+    assert_eq!(stats["num_documents"], 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edit_readme() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // The examples in the README are sent with `curl -d`, as a form.
+    let curl = |body: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/edit/readme")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+            .reply(&filter)
+    };
+    let resp = curl(r#"{"text": "new contents"}"#).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":1}"#);
+    for text in ["new", "new contents"] {
+        let (status, _) = post_edit(&filter, "readme", &json!({ "text": text })).await;
+        assert_eq!(status, 200);
+    }
+    let resp = curl(r#"{"revision": 3, "operation": [12, "appended line\n"]}"#).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":4}"#);
+    expect_text(&filter, "readme", "new contentsappended line\n").await;
+
+    let resp = curl("text=hello").await;
+    assert_eq!(resp.status(), 400);
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Tests for the negotiated binary encoding of messages.
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_msgpack() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_path(&filter, "/api/socket/foobar?encoding=msgpack").await?;
+    assert_eq!(client.recv_msgpack().await?, json!({ "Identity": 0 }));
+
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello\n\"world\"");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send_msgpack(&msg).await;
+
+    let history = json!({
+        "History": {
+            "start": 0,
+            "operations": [
+                { "id": 0, "operation": ["hello\n\"world\""] }
+            ]
+        }
+    });
+    assert_eq!(client.recv_msgpack().await?, history);
+    assert_eq!(client2.recv().await?, history);
+
+    // Text frames are always accepted as JSON.
+    let msg = json!({ "SetLanguage": "python" });
+    client.send(&msg).await;
+    assert_eq!(
+        client.recv_msgpack().await?,
+        json!({ "Language": "python" })
+    );
+    assert_eq!(client2.recv().await?, json!({ "Language": "python" }));
+
+    let mut client3 = connect_path(&filter, "/api/socket/foobar?encoding=msgpack").await?;
+    assert_eq!(client3.recv_msgpack().await?, json!({ "Identity": 2 }));
+    assert_eq!(client3.recv_msgpack().await?, history);
+    assert_eq!(
+        client3.recv_msgpack().await?,
+        json!({ "Language": "python" })
+    );
+
+    expect_text(&filter, "foobar", "hello\n\"world\"").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_encoding() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    assert!(connect_path(&filter, "/api/socket/foobar?encoding=xml")
+        .await
+        .is_err());
+    Ok(())
+}
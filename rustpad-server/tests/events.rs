@@ -0,0 +1,154 @@
+//! Tests for following documents with server-sent events.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+pub mod common;
+
+/// A test client reading server-sent events from a document.
+struct EventStream(BufReader<TcpStream>);
+
+impl EventStream {
+    async fn connect(addr: SocketAddr, path: &str) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        // HTTP/1.0 responses are not chunked, so the body is the event stream.
+        let request = format!("GET {path} HTTP/1.0\r\nHost: {addr}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        if !line.starts_with("HTTP/1.0 200") {
+            bail!("unexpected response: {line}");
+        }
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).await?;
+        }
+        Ok(Self(stream))
+    }
+
+    /// Receive the next event as its name and data, skipping keep-alives.
+    async fn recv(&mut self) -> Result<(String, Value)> {
+        let (mut event, mut data) = (String::new(), String::new());
+        loop {
+            let mut line = String::new();
+            let read = time::timeout(Duration::from_secs(5), self.0.read_line(&mut line)).await?;
+            if read? == 0 {
+                bail!("event stream closed");
+            }
+            let line = line.trim_end_matches('\n');
+            if let Some(value) = line.strip_prefix("event:") {
+                event = value.to_owned();
+            } else if let Some(value) = line.strip_prefix("data:") {
+                data = value.to_owned();
+            } else if line.is_empty() && !event.is_empty() {
+                return Ok((event, serde_json::from_str(&data)?));
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_events() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let mut events = EventStream::connect(addr, "/api/events/foobar").await?;
+    assert_eq!(
+        events.recv().await?,
+        (
+            "History".into(),
+            json!({ "start": 0, "operations": [{ "id": 0, "operation": ["hello"] }] })
+        )
+    );
+
+    let alice = json!({ "name": "Alice", "hue": 42 });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+    let cursors = json!({ "cursors": [5], "selections": [] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 1, "operation": [5, "!"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // Cursor movements are not sent to followers.
+    assert_eq!(
+        events.recv().await?,
+        ("UserInfo".into(), json!({ "id": 0, "info": alice }))
+    );
+    assert_eq!(events.recv().await?, ("Language".into(), json!("rust")));
+    assert_eq!(
+        events.recv().await?,
+        (
+            "History".into(),
+            json!({ "start": 1, "operations": [{ "id": 0, "operation": [5, "!"] }] })
+        )
+    );
+
+    // Connections that leave without presence information are not announced.
+    let mut events2 = EventStream::connect(addr, "/api/events/foobar").await?;
+    events2.recv().await?;
+    drop(events2);
+    let lurker = connect(&filter, "foobar").await?;
+    drop(lurker);
+    time::sleep(Duration::from_millis(50)).await;
+    let msg = json!({ "Edit": { "revision": 2, "operation": [6, "?"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    assert_eq!(
+        events.recv().await?,
+        (
+            "History".into(),
+            json!({ "start": 2, "operations": [{ "id": 0, "operation": [6, "?"] }] })
+        )
+    );
+
+    drop(client);
+    assert_eq!(
+        events.recv().await?,
+        ("UserInfo".into(), json!({ "id": 0, "info": null }))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_events_snapshot() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    for (revision, text) in ["a", "b", "c"].into_iter().enumerate() {
+        let msg = json!({ "Edit": { "revision": revision, "operation": [revision, text] } });
+        client.send(&msg).await;
+        client.recv().await?;
+    }
+
+    let mut events = EventStream::connect(addr, "/api/events/foobar?snapshot=true").await?;
+    assert_eq!(
+        events.recv().await?,
+        ("Snapshot".into(), json!({ "revision": 3, "text": "abc" }))
+    );
+    Ok(())
+}
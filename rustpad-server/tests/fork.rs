@@ -0,0 +1,135 @@
+//! Tests for forking documents.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    database::{Database, PersistedDocument},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Send a request, returning the status and response body.
+async fn request(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    path: &str,
+) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method(method)
+        .path(path)
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_fork() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "question").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["fn main() {}\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 1, "operation": [13, "// TODO\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    let (status, body) = request(&filter, "POST", "/api/document/question/fork").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["revision"], 2);
+    let fork = body["id"].as_str().expect("should return the new id");
+    assert_eq!(fork.len(), 12);
+    expect_text(&filter, fork, "fn main() {}\n// TODO\n").await;
+    let (status, body) = request(&filter, "GET", &format!("/api/document/{fork}")).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "language": "rust", "parent": "question" }));
+
+    // The fork is a separate document, which starts from one insertion.
+    let mut client2 = connect(&filter, fork).await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 0 }));
+    client2.recv().await?;
+    assert_eq!(client2.recv().await?, json!({ "Language": "rust" }));
+    let msg = json!({ "Edit": { "revision": 1, "operation": [21, "answer\n"] } });
+    client2.send(&msg).await;
+    client2.recv().await?;
+    expect_text(&filter, fork, "fn main() {}\n// TODO\nanswer\n").await;
+    expect_text(&filter, "question", "fn main() {}\n// TODO\n").await;
+
+    // Earlier revisions can be forked as well.
+    let path = "/api/document/question/fork?revision=1";
+    let (status, body) = request(&filter, "POST", path).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["revision"], 1);
+    let fork2 = body["id"].as_str().expect("should return the new id");
+    assert_ne!(fork, fork2);
+    expect_text(&filter, fork2, "fn main() {}\n").await;
+
+    let path = "/api/document/question/fork?revision=3";
+    let (status, _) = request(&filter, "POST", path).await;
+    assert_eq!(status, 400);
+
+    let (_, body) = request(&filter, "GET", "/api/document/question").await;
+    assert_eq!(body, json!({ "language": "rust", "parent": null }));
+
+    // Documents that do not exist are not created by forking them.
+    let (status, _) = request(&filter, "POST", "/api/document/missing/fork").await;
+    assert_eq!(status, 404);
+    let (_, stats) = request(&filter, "GET", "/api/stats").await;
+    assert_eq!(stats["num_documents"], 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fork_persist() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let path = NamedTempFile::new()?.into_temp_path();
+    let uri = format!("sqlite://{}", path.to_str().expect("invalid path"));
+    let database = Database::new(&uri).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+
+    // Documents that are only in the database can be forked.
+    let document = PersistedDocument {
+        text: "hello".into(),
+        language: Some("markdown".into()),
+        parent: None,
+    };
+    database.store("question", &document).await?;
+    let (status, body) = request(&filter, "POST", "/api/document/question/fork").await;
+    assert_eq!(status, 200);
+    let fork = body["id"].as_str().expect("should return the new id");
+    expect_text(&filter, fork, "hello").await;
+
+    // The fork is stored with its parent by the usual persister.
+    let stored = time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Ok(document) = database.load(fork).await {
+                return document;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    assert_eq!(stored.text, "hello");
+    assert_eq!(stored.language.as_deref(), Some("markdown"));
+    assert_eq!(stored.parent.as_deref(), Some("question"));
+
+    let (status, _) = request(&filter, "POST", "/api/document/missing/fork").await;
+    assert_eq!(status, 404);
+    assert!(database.load("missing").await.is_err());
+    Ok(())
+}
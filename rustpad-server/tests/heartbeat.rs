@@ -0,0 +1,125 @@
+//! Tests for detecting dead connections with pings.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use futures::prelude::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+use tokio::time;
+use tokio_tungstenite::tungstenite::Message;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_ghost_user() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ping_interval: Some(Duration::from_millis(50)),
+        ping_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // This client sends its information, then stops reading from the socket,
+    // so it never replies to pings.
+    let url = format!("ws://{addr}/api/socket/foobar");
+    let (mut ghost, _) = tokio_tungstenite::connect_async(url).await?;
+    let alice = json!({
+        "name": "Alice",
+        "hue": 42
+    });
+    let msg = json!({ "ClientInfo": alice });
+    ghost.send(Message::text(msg.to_string())).await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 1, "info": alice } })
+    );
+
+    let msg = time::timeout(Duration::from_secs(2), client.recv()).await??;
+    assert_eq!(msg, json!({ "UserInfo": { "id": 1, "info": null } }));
+
+    // Clients that reply to pings stay connected.
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    drop(ghost);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stalled_reader() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ping_interval: Some(Duration::from_millis(50)),
+        ping_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // This client keeps sending messages, but never reads from the socket, so
+    // writes to it block once the network buffers are full.
+    let url = format!("ws://{addr}/api/socket/foobar");
+    let (ghost, _) = tokio_tungstenite::connect_async(url).await?;
+    let (mut ghost, _reader) = ghost.split();
+    let alice = json!({
+        "name": "Alice",
+        "hue": 42
+    });
+    let msg = json!({ "ClientInfo": alice });
+    ghost.send(Message::text(msg.to_string())).await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 1, "info": alice } })
+    );
+    let keepalive = tokio::spawn(async move {
+        loop {
+            time::sleep(Duration::from_millis(20)).await;
+            if ghost.send(Message::Pong(Vec::new())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Insert and delete a large block of text repeatedly.
+    let block = "a".repeat(64 * 1024);
+    let mut revision = 0;
+    let disconnected = json!({ "UserInfo": { "id": 1, "info": null } });
+    let result = time::timeout(Duration::from_secs(5), async {
+        loop {
+            let mut operation = OperationSeq::default();
+            if revision % 2 == 0 {
+                operation.insert(&block);
+            } else {
+                operation.delete(block.len() as u64);
+            }
+            let msg = json!({ "Edit": { "revision": revision, "operation": operation } });
+            client.send(&msg).await;
+            revision += 1;
+            loop {
+                let msg = client.recv().await?;
+                if msg == disconnected {
+                    return anyhow::Ok(());
+                }
+                if msg.get("History").is_some() {
+                    break;
+                }
+            }
+        }
+    })
+    .await;
+    result.expect("stalled client was not disconnected")?;
+
+    keepalive.abort();
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Tests for detecting the language of documents.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+
+pub mod common;
+
+const PYTHON: &str = "\
+import sys
+
+def main(args):
+    for arg in args:
+        if arg == \"-h\":
+            print(\"usage\")
+        elif arg is None:
+            pass
+
+if __name__ == \"__main__\":
+    main(sys.argv)
+";
+
+#[tokio::test]
+async fn test_detect_language() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // Short texts are not enough to detect a language.
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["import sys\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let operation = json!([11, &PYTHON[11..]]);
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["start"], 1);
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+
+    // A language that is already set is not replaced.
+    let msg = json!({ "SetLanguage": "plaintext" });
+    client.send(&msg).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "plaintext" }));
+    let operation = json!([PYTHON.len(), PYTHON]);
+    let msg = json!({ "Edit": { "revision": 2, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client
+        .send(&json!({ "ClientInfo": { "name": "Alice", "hue": 42 } }))
+        .await;
+    assert!(client.recv().await?.get("UserInfo").is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_detect_heuristics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let cases = [
+        ("#!/usr/bin/python3 -u\nprint(1)\n", json!("python")),
+        (
+            "#!/usr/bin/env -S node --no-warnings\n",
+            json!("javascript"),
+        ),
+        ("# vim: set ft=sh ts=2:\necho hi\n", json!("shell")),
+        ("/* -*- mode: c++; -*- */\nint x;\n", json!("cpp")),
+        ("<?xml version=\"1.0\"?>\n<a/>\n", json!("xml")),
+        ("<!DOCTYPE html>\n<p>hi</p>\n", json!("html")),
+        ("{\"a\": [1, 2, {\"b\": null}]}", json!("json")),
+        (
+            "use std::io;\n\nfn main() {\n    let mut s = String::new();\n    \
+             io::stdin().read_line(&mut s).unwrap();\n    println!(\"{}\", s);\n}\n",
+            json!("rust"),
+        ),
+        (
+            "package main\n\nimport \"fmt\"\n\nfunc main() {\n\tx := 1\n\t\
+             if err != nil {\n\t\treturn\n\t}\n\tfmt.Println(x)\n}\n",
+            json!("go"),
+        ),
+        (
+            "# Meeting notes\n\nWe discussed the plan for this week and agreed to \
+             meet again on Friday to review the results.\n",
+            json!(null),
+        ),
+    ];
+    for (i, (text, language)) in cases.into_iter().enumerate() {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/text/doc{i}"))
+            .body(text)
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = serde_json::from_slice(resp.body())?;
+        assert_eq!(body["language"], language, "language of {text:?}");
+    }
+
+    // Only the start of long texts is parsed as JSON.
+    for (text, language) in [
+        (format!("[{}1]", "1, ".repeat(10000)), json!("json")),
+        (format!("[{}1", "1, ".repeat(10000)), json!("json")),
+        (format!("[1] {}", "x".repeat(20000)), json!(null)),
+    ] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/text/long")
+            .body(text)
+            .reply(&filter)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body())?;
+        assert_eq!(body["language"], language);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_validate_language() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // Aliases, extensions and other cases are normalized to language IDs.
+    for (name, language) in [
+        ("py", "python"),
+        ("TypeScript", "typescript"),
+        (" golang ", "go"),
+        ("c++", "cpp"),
+        ("sol", "sol"),
+    ] {
+        client.send(&json!({ "SetLanguage": name })).await;
+        assert_eq!(client.recv().await?, json!({ "Language": language }));
+    }
+
+    client.send(&json!({ "SetLanguage": "klingon" })).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Error": "unknown language \"klingon\"" }));
+
+    client
+        .send(&json!({ "SetLanguage": "x".repeat(1000) }))
+        .await;
+    let msg = client.recv().await?;
+    let error = msg["Error"].as_str().expect("should receive an error");
+    assert!(error.contains("longer than"));
+
+    // Rejected languages are not broadcast, and leave the language unchanged.
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?, json!({ "Language": "sol" }));
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/foobar?language=klingon")
+        .body("qapla'")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+    expect_text(&filter, "foobar", "").await;
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/foobar?language=rs")
+        .body("fn main() {}")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["language"], "rust");
+    Ok(())
+}
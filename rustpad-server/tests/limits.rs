@@ -0,0 +1,279 @@
+//! Tests for limits on the number of connections and documents.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use futures::future;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Attempt a WebSocket upgrade, returning the HTTP status code.
+async fn upgrade_status(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> u16 {
+    let resp = warp::test::request()
+        .path(&format!("/api/socket/{}", id))
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(filter)
+        .await;
+    resp.status().as_u16()
+}
+
+/// Open a document and set its language, so it is kept after disconnecting.
+async fn open(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> Result<JsonSocket> {
+    let mut client = connect(filter, id).await?;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    while client.recv().await? != json!({ "Language": "rust" }) {}
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_max_connections() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foo").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(upgrade_status(&filter, "bar").await, 503);
+    assert!(connect(&filter, "foo").await.is_err());
+
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    let mut client = connect(&filter, "bar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_document_connections() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_document_connections: Some(2),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foo").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut client2 = connect(&filter, "foo").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(upgrade_status(&filter, "foo").await, 503);
+
+    let mut client3 = connect(&filter, "bar").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_documents() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_documents: Some(1),
+        ..ServerConfig::default()
+    });
+
+    let client = open(&filter, "foo").await?;
+    assert_eq!(upgrade_status(&filter, "bar").await, 503);
+
+    // Documents are not evicted by default, even without connections.
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(upgrade_status(&filter, "bar").await, 503);
+    connect(&filter, "foo").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_evict_lru() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_documents: Some(2),
+        evict_lru: true,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foo").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+    expect_text(&filter, "foo", "hello").await;
+
+    let client2 = open(&filter, "bar").await?;
+    drop(client2);
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The idle document is evicted, but not the one with a connection, and
+    // new documents are closed when they are first edited if there is no room.
+    let _client3 = open(&filter, "baz").await?;
+    expect_text(&filter, "foo", "hello").await;
+    let mut client5 = connect(&filter, "qux").await?;
+    assert_eq!(client5.recv().await?, json!({ "Identity": 0 }));
+    client5.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client5.recv().await?, json!({ "Language": "rust" }));
+    client5.recv_closed().await?;
+
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    let _client4 = open(&filter, "qux").await?;
+    expect_text(&filter, "foo", "").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_visitors_do_not_evict() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_documents: Some(1),
+        evict_lru: true,
+        ..ServerConfig::default()
+    });
+
+    let client = open(&filter, "foo").await?;
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Documents that are only visited take no room until they are edited.
+    let mut visitor = connect(&filter, "empty").await?;
+    assert_eq!(visitor.recv().await?, json!({ "Identity": 0 }));
+    let resp = warp::test::request()
+        .path("/api/document/foo")
+        .reply(&filter)
+        .await;
+    let document: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(document["language"], "rust");
+
+    visitor.send(&json!({ "SetLanguage": "go" })).await;
+    assert_eq!(visitor.recv().await?, json!({ "Language": "go" }));
+    time::sleep(Duration::from_millis(50)).await;
+    let resp = warp::test::request()
+        .path("/api/document/foo")
+        .reply(&filter)
+        .await;
+    let document: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(document["language"], Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_budget() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        memory_budget: Some(1000),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foo").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let _client2 = connect(&filter, "bar").await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert(&"a".repeat(1000));
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    assert_eq!(upgrade_status(&filter, "baz").await, 503);
+    connect(&filter, "bar").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_opens() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_documents: Some(3),
+        ..ServerConfig::default()
+    });
+
+    let requests = (0..20).map(|i| {
+        warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/text/doc{i}"))
+            .body("hello")
+            .reply(&filter)
+    });
+    let statuses: Vec<_> = future::join_all(requests)
+        .await
+        .iter()
+        .map(|resp| resp.status().as_u16())
+        .collect();
+    assert_eq!(statuses.iter().filter(|&&status| status == 200).count(), 3);
+    assert_eq!(statuses.iter().filter(|&&status| status == 503).count(), 17);
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["num_documents"], 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_evict_on_growth() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        memory_budget: Some(1000),
+        evict_lru: true,
+        ..ServerConfig::default()
+    });
+
+    let client = open(&filter, "foo").await?;
+    drop(client);
+    let mut client = connect(&filter, "bar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Edits past the budget evict idle documents without opening new ones.
+    let mut operation = OperationSeq::default();
+    operation.insert(&"a".repeat(1000));
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["num_documents"], 1);
+    expect_text(&filter, "bar", &"a".repeat(1000)).await;
+
+    Ok(())
+}
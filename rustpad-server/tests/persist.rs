@@ -38,6 +38,7 @@ async fn test_database() -> Result<()> {
     let doc1 = PersistedDocument {
         text: "Hello Text".into(),
         language: None,
+        parent: None,
     };
 
     assert!(database.store("hello", &doc1).await.is_ok());
@@ -47,6 +48,7 @@ async fn test_database() -> Result<()> {
     let doc2 = PersistedDocument {
         text: "print('World Text :)')".into(),
         language: Some("python".into()),
+        parent: Some("hello".into()),
     };
 
     assert!(database.store("world", &doc2).await.is_ok());
@@ -66,6 +68,7 @@ async fn test_persist() -> Result<()> {
     let filter = server(ServerConfig {
         expiry_days: 2,
         database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        ..ServerConfig::default()
     });
 
     expect_text(&filter, "persist", "").await;
@@ -104,3 +107,67 @@ async fn test_persist() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_persisted_language() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    for (id, language) in [("alias", "Py"), ("unknown", "klingon")] {
+        let document = PersistedDocument {
+            text: "hello".into(),
+            language: Some(language.into()),
+            parent: None,
+        };
+        database.store(id, &document).await?;
+    }
+    let filter = server(ServerConfig {
+        database: Some(database),
+        ..ServerConfig::default()
+    });
+
+    // Languages stored without validation are normalized when loaded, or kept
+    // until a client replaces them if they are unknown.
+    let mut client = connect(&filter, "alias").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?;
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+
+    let mut client = connect(&filter, "unknown").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?;
+    assert_eq!(client.recv().await?, json!({ "Language": "klingon" }));
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_evict_persist() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        max_documents: Some(1),
+        evict_lru: true,
+        ..ServerConfig::default()
+    });
+    let upload = |id: &str, text: &'static str| {
+        warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/text/{id}"))
+            .body(text)
+            .reply(&filter)
+    };
+
+    // Evicted documents are stored before they can be read or opened again.
+    assert_eq!(upload("foo", "hello").await.status(), 200);
+    assert_eq!(upload("bar", "world").await.status(), 200);
+    expect_text(&filter, "foo", "hello").await;
+    assert_eq!(upload("foo", "hello again").await.status(), 200);
+    expect_text(&filter, "bar", "world").await;
+    assert_eq!(upload("bar", "world again").await.status(), 200);
+    expect_text(&filter, "foo", "hello again").await;
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Tests for protocol version negotiation.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_hello() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_path(&filter, "/api/socket/foobar?version=1").await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Hello": { "version": 1, "features": [] } })
+    );
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let path = "/api/socket/foobar?version=1&encoding=msgpack&snapshot=true";
+    let mut client2 = connect_path(&filter, path).await?;
+    assert_eq!(
+        client2.recv_msgpack().await?,
+        json!({ "Hello": { "version": 1, "features": ["msgpack", "snapshot"] } })
+    );
+    assert_eq!(client2.recv_msgpack().await?, json!({ "Identity": 1 }));
+
+    // Clients that do not give a version receive no hello.
+    let mut client3 = connect(&filter, "foobar").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hello_without_compression() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        compression: false,
+        ..ServerConfig::default()
+    });
+
+    // Only the features in effect are listed.
+    let path = "/api/socket/foobar?version=1&encoding=json-deflate";
+    let mut client = connect_path(&filter, path).await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Hello": { "version": 1, "features": [] } })
+    );
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_version() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // The client is rejected before it receives any state.
+    let mut client = connect_path(&filter, "/api/socket/foobar?version=2").await?;
+    let msg = client.recv().await?;
+    let error = msg["Error"].as_str().expect("should receive an error");
+    assert!(error.contains("unsupported protocol version 2"));
+    client.recv_closed().await?;
+
+    Ok(())
+}
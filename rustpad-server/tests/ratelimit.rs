@@ -0,0 +1,195 @@
+//! Tests for rate limiting of client messages.
+
+use anyhow::{anyhow, Result};
+use common::*;
+use futures::prelude::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{ratelimit::RateLimit, server, ServerConfig};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+const LIMIT: RateLimit = RateLimit {
+    per_second: 0.001,
+    burst: 2,
+};
+
+#[tokio::test]
+async fn test_connection_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        connection_rate_limit: Some(LIMIT),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let cursors = json!({ "cursors": [0], "selections": [] });
+    for _ in 0..3 {
+        client.send(&json!({ "CursorData": cursors })).await;
+    }
+    let user_cursor = json!({ "UserCursor": { "id": 0, "data": cursors } });
+    let error = json!({ "Error": "rate limit exceeded, message dropped" });
+    let mut msgs = Vec::new();
+    for _ in 0..3 {
+        msgs.push(client.recv().await?);
+    }
+    // The error is sent directly, so it may arrive before the broadcasts.
+    assert_eq!(msgs.iter().filter(|&msg| msg == &user_cursor).count(), 2);
+    assert_eq!(msgs.iter().filter(|&msg| msg == &error).count(), 1);
+
+    // Other clients are not affected.
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?, user_cursor);
+    client2.send(&json!({ "CursorData": cursors })).await;
+    let user_cursor2 = json!({ "UserCursor": { "id": 1, "data": cursors } });
+    assert_eq!(client2.recv().await?, user_cursor2);
+    assert_eq!(client.recv().await?, user_cursor2);
+
+    // Edits over the limit close the connection rather than being dropped.
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    assert_eq!(client.recv().await?, error);
+    client.recv_closed().await?;
+
+    expect_text(&filter, "foobar", "").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ip_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ip_rate_limit: Some(LIMIT),
+        ..ServerConfig::default()
+    });
+    let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let url = format!("ws://{}/api/socket/foobar", addr);
+    let (mut client, _) = tokio_tungstenite::connect_async(&url).await?;
+    let (mut client2, _) = tokio_tungstenite::connect_async(&url).await?;
+
+    async fn recv<S>(socket: &mut S) -> Result<Value>
+    where
+        S: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let msg = socket.next().await.ok_or_else(|| anyhow!("closed"))??;
+        Ok(serde_json::from_str(msg.to_text()?)?)
+    }
+    assert_eq!(recv(&mut client).await?, json!({ "Identity": 0 }));
+    assert_eq!(recv(&mut client2).await?, json!({ "Identity": 1 }));
+
+    // Both connections come from the same address, so they share one bucket.
+    let language = |lang: &str| Message::text(json!({ "SetLanguage": lang }).to_string());
+    client.send(language("python")).await?;
+    assert_eq!(recv(&mut client).await?, json!({ "Language": "python" }));
+    client2.send(language("rust")).await?;
+    assert_eq!(recv(&mut client2).await?, json!({ "Language": "python" }));
+    assert_eq!(recv(&mut client2).await?, json!({ "Language": "rust" }));
+    client2.send(language("go")).await?;
+    assert_eq!(
+        recv(&mut client2).await?,
+        json!({ "Error": "rate limit exceeded, message dropped" })
+    );
+
+    Ok(())
+}
+
+/// Send a request to the HTTP API from `addr`, returning the status.
+async fn request(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    path: &str,
+    body: &'static str,
+    addr: [u8; 4],
+) -> u16 {
+    warp::test::request()
+        .method(method)
+        .path(path)
+        .body(body)
+        .remote_addr((addr, 1234).into())
+        .reply(filter)
+        .await
+        .status()
+        .as_u16()
+}
+
+#[tokio::test]
+async fn test_http_ip_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ip_rate_limit: Some(LIMIT),
+        ..ServerConfig::default()
+    });
+
+    // Edits, uploads and forks from one address share its bucket.
+    let edit = r#"{"text": "hello"}"#;
+    assert_eq!(
+        request(&filter, "POST", "/api/edit/foo", edit, [1, 1, 1, 1]).await,
+        200
+    );
+    assert_eq!(
+        request(&filter, "PUT", "/api/text/bar", "hi", [1, 1, 1, 1]).await,
+        200
+    );
+    assert_eq!(
+        request(&filter, "POST", "/api/document/foo/fork", "", [1, 1, 1, 1]).await,
+        429
+    );
+    assert_eq!(
+        request(&filter, "PUT", "/api/text/baz", "hi", [1, 1, 1, 1]).await,
+        429
+    );
+    expect_text(&filter, "baz", "").await;
+
+    // Other addresses are not affected.
+    assert_eq!(
+        request(&filter, "POST", "/api/document/foo/fork", "", [2, 2, 2, 2]).await,
+        200
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_document_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        connection_rate_limit: Some(LIMIT),
+        ..ServerConfig::default()
+    });
+
+    // Requests to one document share its bucket, whatever their address.
+    let edit = r#"{"text": "hello"}"#;
+    assert_eq!(
+        request(&filter, "POST", "/api/edit/foo", edit, [1, 1, 1, 1]).await,
+        200
+    );
+    assert_eq!(
+        request(&filter, "PUT", "/api/text/foo", "hi", [2, 2, 2, 2]).await,
+        200
+    );
+    assert_eq!(
+        request(&filter, "POST", "/api/document/foo/fork", "", [3, 3, 3, 3]).await,
+        429
+    );
+    expect_text(&filter, "foo", "hi").await;
+
+    // Other documents are not affected.
+    assert_eq!(
+        request(&filter, "POST", "/api/edit/bar", edit, [1, 1, 1, 1]).await,
+        200
+    );
+    Ok(())
+}
@@ -228,3 +228,213 @@ async fn test_set_language() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_late_joiners() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let history = json!({
+        "History": {
+            "start": 0,
+            "operations": [
+                { "id": 0, "operation": ["hello"] }
+            ]
+        }
+    });
+    let mut joiners = Vec::new();
+    for id in 1..=2 {
+        let mut client = connect(&filter, "foobar").await?;
+        assert_eq!(client.recv().await?, json!({ "Identity": id }));
+        assert_eq!(client.recv().await?, history);
+        joiners.push(client);
+    }
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+    for client in &mut joiners {
+        client.recv().await?;
+    }
+
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 3 }));
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": 0, "operation": ["hello"] },
+                    { "id": 0, "operation": [5, " world"] }
+                ]
+            }
+        })
+    );
+
+    expect_text(&filter, "foobar", "hello world").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compression() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_path(&filter, "/api/socket/foobar?encoding=json-deflate").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let text = "hello world\n".repeat(200);
+    let mut operation = OperationSeq::default();
+    operation.insert(&text);
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+
+    let history = json!({
+        "History": {
+            "start": 0,
+            "operations": [
+                { "id": 0, "operation": [text] }
+            ]
+        }
+    });
+    assert_eq!(client.recv_deflate().await?, history);
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let mut client2 = connect_path(&filter, "/api/socket/foobar?encoding=json-deflate").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv_deflate().await?, history);
+    assert_eq!(client2.recv().await?, json!({ "Language": "rust" }));
+
+    let mut client3 = connect(&filter, "foobar").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    assert_eq!(client3.recv().await?, history);
+
+    expect_text(&filter, "foobar", &text).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compression_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        compression: false,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect_path(&filter, "/api/socket/foobar?encoding=json-deflate").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let text = "hello world\n".repeat(200);
+    let mut operation = OperationSeq::default();
+    operation.insert(&text);
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["operations"][0]["operation"], json!([text]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    let mut client2 = connect_path(&filter, "/api/socket/foobar?snapshot=true").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(
+        client2.recv().await?,
+        json!({ "Snapshot": { "revision": 2, "text": "hello world" } })
+    );
+    assert_eq!(client2.recv().await?, json!({ "Language": "rust" }));
+
+    let mut operation = OperationSeq::default();
+    operation.retain(11);
+    operation.insert("!");
+    let msg = json!({
+        "Edit": {
+            "revision": 2,
+            "operation": operation
+        }
+    });
+    client2.send(&msg).await;
+
+    let history = json!({
+        "History": {
+            "start": 2,
+            "operations": [
+                { "id": 1, "operation": [11, "!"] }
+            ]
+        }
+    });
+    assert_eq!(client2.recv().await?, history);
+    assert_eq!(client.recv().await?, history);
+
+    expect_text(&filter, "foobar", "hello world!").await;
+    Ok(())
+}
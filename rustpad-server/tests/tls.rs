@@ -0,0 +1,158 @@
+//! Tests for TLS termination and certificate reloading.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::prelude::*;
+use rustpad_server::{ratelimit::RateLimit, server, tls, ServerConfig};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_rustls::rustls::{self, pki_types::CertificateDer, RootCertStore};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Write a fresh self-signed certificate for `localhost`, returning its DER.
+fn write_cert(dir: &Path) -> Result<CertificateDer<'static>> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    std::fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+    std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+    Ok(cert.serialize_der()?.into())
+}
+
+/// Connect to the server trusting only `cert`.
+async fn connect_tls(port: u16, cert: &CertificateDer<'static>) -> Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone())?;
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    Ok(connector.connect("localhost".try_into()?, stream).await?)
+}
+
+/// Make an HTTPS request trusting only `cert`, returning the status line.
+async fn get(port: u16, cert: &CertificateDer<'static>, path: &str) -> Result<String> {
+    let mut stream = connect_tls(port, cert).await?;
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.windows(2).any(|w| w == b"\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let response = String::from_utf8(response)?;
+    Ok(response.lines().next().unwrap_or_default().into())
+}
+
+#[tokio::test]
+async fn test_tls_reload() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let dir = TempDir::new()?;
+    let old_cert = write_cert(dir.path())?;
+    let config = tls::TlsConfig {
+        cert_path: dir.path().join("cert.pem"),
+        key_path: dir.path().join("key.pem"),
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(tls::serve(
+        server(ServerConfig::default()),
+        listener,
+        config,
+    )?);
+
+    assert_eq!(
+        get(port, &old_cert, "/api/text/tls").await?,
+        "HTTP/1.1 200 OK"
+    );
+
+    let new_cert = write_cert(dir.path())?;
+    assert_eq!(
+        get(port, &old_cert, "/api/text/tls").await?,
+        "HTTP/1.1 200 OK"
+    );
+
+    time::pause();
+    time::advance(Duration::from_secs(60)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        get(port, &new_cert, "/api/text/tls").await?,
+        "HTTP/1.1 200 OK"
+    );
+    assert!(get(port, &old_cert, "/api/text/tls").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_ip_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let dir = TempDir::new()?;
+    let cert = write_cert(dir.path())?;
+    let config = tls::TlsConfig {
+        cert_path: dir.path().join("cert.pem"),
+        key_path: dir.path().join("key.pem"),
+    };
+    let filter = server(ServerConfig {
+        ip_rate_limit: Some(RateLimit {
+            per_second: 0.001,
+            burst: 1,
+        }),
+        ..ServerConfig::default()
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(tls::serve(filter, listener, config)?);
+
+    // The remote address of TLS connections is known, so limits per address
+    // apply across connections.
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let stream = connect_tls(port, &cert).await?;
+        let url = "wss://localhost/api/socket/foobar";
+        let (mut client, _) = tokio_tungstenite::client_async(url, stream).await?;
+        client.next().await.expect("should receive identity")?;
+        clients.push(client);
+    }
+    async fn recv(
+        client: &mut (impl Stream<Item = tungstenite::Result<Message>> + Unpin),
+    ) -> Result<Value> {
+        let msg = client.next().await.expect("should receive a message")?;
+        Ok(serde_json::from_str(msg.to_text()?)?)
+    }
+    let language = |lang: &str| Message::text(json!({ "SetLanguage": lang }).to_string());
+    clients[0].send(language("python")).await?;
+    assert_eq!(
+        recv(&mut clients[0]).await?,
+        json!({ "Language": "python" })
+    );
+    assert_eq!(
+        recv(&mut clients[1]).await?,
+        json!({ "Language": "python" })
+    );
+    clients[1].send(language("rust")).await?;
+    assert_eq!(
+        recv(&mut clients[1]).await?,
+        json!({ "Error": "rate limit exceeded, message dropped" })
+    );
+
+    Ok(())
+}
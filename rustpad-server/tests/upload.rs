@@ -0,0 +1,157 @@
+//! Tests for uploading files to documents.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Upload a file, returning the status and response body.
+async fn upload(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    path: &str,
+    body: impl AsRef<[u8]>,
+) -> (u16, String) {
+    let resp = warp::test::request()
+        .method("PUT")
+        .path(path)
+        .body(body)
+        .reply(filter)
+        .await;
+    let body = String::from_utf8_lossy(resp.body()).into_owned();
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_upload_replace() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["print(1)\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let path = "/api/text/foobar?filename=script.py";
+    let (status, body) = upload(&filter, path, "print(1)\nprint(2)\n").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 2, "language": "python" })
+    );
+
+    // The replacement is an ordinary edit, so cursors before it are kept.
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [{ "id": 1, "operation": [9, "print(2)\n"] }]
+            }
+        })
+    );
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+    expect_text(&filter, "foobar", "print(1)\nprint(2)\n").await;
+
+    // A language that is given overrides the file name.
+    let path = "/api/text/foobar?filename=notes.txt&language=markdown";
+    let (status, body) = upload(&filter, path, "# Notes\n").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 3, "language": "markdown" })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_new() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let script = "#!/usr/bin/env bash\necho hello\n";
+    let (status, body) = upload(&filter, "/api/text/script", script).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 1, "language": "shell" })
+    );
+    expect_text(&filter, "script", script).await;
+
+    let mut client = connect(&filter, "script").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv().await?;
+    assert_eq!(client.recv().await?, json!({ "Language": "shell" }));
+
+    // Files without a recognizable language leave it unset.
+    let (status, body) = upload(&filter, "/api/text/plain", "just text").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 1, "language": null })
+    );
+
+    let (status, _) = upload(&filter, "/api/text/binary", [0xff, 0xfe, 0x00]).await;
+    assert_eq!(status, 400);
+    expect_text(&filter, "binary", "").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_after_edit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["draft\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // The upload is diffed against the latest text, so edits made before it
+    // are replaced rather than merged.
+    let msg = json!({ "Edit": { "revision": 1, "operation": [6, "more\n"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let path = "/api/text/foobar?language=plaintext";
+    let (status, body) = upload(&filter, path, "final\n").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "revision": 3, "language": "plaintext" })
+    );
+    expect_text(&filter, "foobar", "final\n").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_rejected() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let (status, _) = upload(&filter, "/api/text/foobar", "hello").await;
+    assert_eq!(status, 200);
+
+    // Uploads without a length, such as chunked ones, are not read.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/foobar")
+        .header("transfer-encoding", "chunked")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 411);
+
+    let (status, _) = upload(&filter, "/api/text/foobar", "x".repeat(1024 * 1024 + 1)).await;
+    assert_eq!(status, 413);
+
+    // Texts that are too long are rejected before they are compared.
+    let (status, body) = upload(&filter, "/api/text/foobar", "x".repeat(300 * 1024)).await;
+    assert_eq!(status, 400);
+    assert!(body.contains("256 KiB"));
+    expect_text(&filter, "foobar", "hello").await;
+    Ok(())
+}
@@ -74,6 +74,10 @@ async fn test_leave_rejoin() -> Result<()> {
     let mut client = connect(&filter, "foobar").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
 
+    // Touch the document, so that it is kept after the only client leaves.
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
     let alice = json!({
         "name": "Alice",
         "hue": 42
@@ -93,6 +97,7 @@ async fn test_leave_rejoin() -> Result<()> {
 
     let mut client2 = connect(&filter, "foobar").await?;
     assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?, json!({ "Language": "rust" }));
 
     let bob = json!({
         "name": "Bob",
@@ -180,3 +185,57 @@ async fn test_cursors() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_lagged_updates() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        update_capacity: 1,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+
+    let alice = json!({
+        "name": "Alice",
+        "hue": 42
+    });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    for i in 0..100 {
+        let cursors = json!({ "cursors": [i], "selections": [] });
+        client.send(&json!({ "CursorData": cursors })).await;
+    }
+
+    // The second client falls behind, but still ends up with the latest state.
+    let last_cursor = json!({
+        "UserCursor": {
+            "id": 0,
+            "data": { "cursors": [99], "selections": [] }
+        }
+    });
+    let mut messages = Vec::new();
+    loop {
+        let msg = client2.recv().await?;
+        messages.push(msg.clone());
+        if msg == last_cursor {
+            break;
+        }
+    }
+    assert!(messages.contains(&json!({ "UserInfo": { "id": 0, "info": alice } })));
+    assert!(messages.len() < 101, "expected some updates to be skipped");
+
+    // ... and remains connected.
+    client2.send(&json!({ "SetLanguage": "rust" })).await;
+    loop {
+        let msg = client2.recv().await?;
+        if msg == json!({ "Language": "rust" }) {
+            break;
+        }
+        assert_eq!(msg, last_cursor);
+    }
+
+    Ok(())
+}
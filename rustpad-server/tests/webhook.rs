@@ -0,0 +1,115 @@
+//! Tests for webhooks sent after documents are edited.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time;
+use warp::Filter;
+
+pub mod common;
+
+/// Start a local HTTP server that forwards the bodies of requests it receives.
+fn receiver() -> (String, mpsc::UnboundedReceiver<Value>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let route = warp::post()
+        .and(warp::body::json())
+        .map(move |body: Value| {
+            tx.send(body).ok();
+            "ok"
+        });
+    let (addr, serve) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+    (format!("http://{addr}/hook"), rx)
+}
+
+fn sha256(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text))
+}
+
+#[tokio::test]
+async fn test_webhooks() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let (url, mut hooks) = receiver();
+    let filter = server(ServerConfig {
+        webhooks: vec![url.parse()?],
+        webhook_delay: Duration::from_millis(100),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // Edits in quick succession are sent in one request.
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 1, "operation": [5, " world"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let hook = time::timeout(Duration::from_secs(2), hooks.recv()).await?;
+    assert_eq!(
+        hook,
+        Some(json!({
+            "id": "foobar",
+            "revision": 2,
+            "changes": [{ "start": 0, "end": 11 }],
+            "sha256": sha256("hello world"),
+        }))
+    );
+
+    // Presence updates do not trigger webhooks.
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 2, "operation": ["\u{1f600}", 11] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "revision": 3, "operation": [7, "there", -5] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let hook = time::timeout(Duration::from_secs(2), hooks.recv()).await?;
+    assert_eq!(
+        hook,
+        Some(json!({
+            "id": "foobar",
+            "revision": 4,
+            "changes": [{ "start": 0, "end": 1 }, { "start": 7, "end": 12 }],
+            "sha256": sha256("\u{1f600}hello there"),
+        }))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_https_webhooks() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("https://localhost:{}/hook", listener.local_addr()?.port());
+    let filter = server(ServerConfig {
+        webhooks: vec![url.parse()?],
+        webhook_delay: Duration::from_millis(100),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = json!({ "Edit": { "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // The webhook is sent over TLS, starting with a handshake record.
+    let (mut stream, _) = time::timeout(Duration::from_secs(2), listener.accept()).await??;
+    let mut record = [0; 3];
+    stream.read_exact(&mut record).await?;
+    assert_eq!(record[0], 0x16);
+    assert_eq!(record[1], 0x03);
+    Ok(())
+}
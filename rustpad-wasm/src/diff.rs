@@ -0,0 +1,119 @@
+//! Computing text operations from the difference between two strings.
+//!
+//! This uses the linear space variant of Myers' diff algorithm, described in
+//! "An O(ND) Difference Algorithm and Its Variations" (1986), on Unicode
+//! codepoints.
+
+use operational_transform::OperationSeq;
+
+/// Maximum number of steps taken to diff one range of the texts, after which
+/// the range is replaced wholesale instead. This bounds the running time when
+/// texts with many scattered changes are diffed.
+const MAX_DIFF_COST: usize = 1 << 24;
+
+/// Returns a minimal operation that turns `old` into `new`.
+pub fn diff(old: &str, new: &str) -> OperationSeq {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let mut operation = OperationSeq::default();
+    diff_range(&old, &new, &mut operation);
+    operation
+}
+
+/// Append the operations that turn `a` into `b` to `operation`.
+fn diff_range(a: &[char], b: &[char], operation: &mut OperationSeq) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    operation.retain(prefix as u64);
+    if a.is_empty() || b.is_empty() {
+        replace(a, b, operation);
+    } else if let Some((x, y, u, v)) = middle_snake(a, b) {
+        diff_range(&a[..x], &b[..y], operation);
+        operation.retain((u - x) as u64);
+        diff_range(&a[u..], &b[v..], operation);
+    } else {
+        replace(a, b, operation);
+    }
+    operation.retain(suffix as u64);
+}
+
+fn replace(a: &[char], b: &[char], operation: &mut OperationSeq) {
+    operation.delete(a.len() as u64);
+    operation.insert(&b.iter().collect::<String>());
+}
+
+/// Find the middle snake of an optimal path through the edit graph of two
+/// non-empty sequences that differ at both ends, as `(x, y, u, v)` where the
+/// snake goes from `(x, y)` to `(u, v)`. Returns `None` if this exceeds the
+/// maximum cost.
+fn middle_snake(a: &[char], b: &[char]) -> Option<(usize, usize, usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+
+    // Furthest reaching x on each diagonal k, in the forward direction from
+    // the start and in the backward direction from the end, where backward
+    // coordinates are measured from the end of each sequence.
+    let offset = max + 1;
+    let mut forward = vec![0; 2 * offset as usize + 1];
+    let mut backward = vec![0; 2 * offset as usize + 1];
+    let at = |k: isize| (k + offset) as usize;
+    let mut cost = 0;
+
+    for d in 0..=max {
+        cost += 2 * d as usize + 2;
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            };
+            let (x0, y0) = (x, x - k);
+            while x < n && x - k < m && a[x as usize] == b[(x - k) as usize] {
+                x += 1;
+            }
+            cost += (x - x0) as usize;
+            forward[at(k)] = x;
+            let kb = delta - k;
+            if odd && (-(d - 1)..=d - 1).contains(&kb) && x + backward[at(kb)] >= n {
+                let snake = (x0, y0, x, x - k);
+                return Some(to_usize(snake));
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            };
+            let (x0, y0) = (x, x - k);
+            while x < n && x - k < m && a[(n - 1 - x) as usize] == b[(m - 1 - (x - k)) as usize] {
+                x += 1;
+            }
+            cost += (x - x0) as usize;
+            backward[at(k)] = x;
+            let kf = delta - k;
+            if !odd && (-d..=d).contains(&kf) && x + forward[at(kf)] >= n {
+                let snake = (n - x, m - (x - k), n - x0, m - y0);
+                return Some(to_usize(snake));
+            }
+        }
+        if cost > MAX_DIFF_COST {
+            return None;
+        }
+    }
+    None
+}
+
+fn to_usize((x, y, u, v): (isize, isize, isize, isize)) -> (usize, usize, usize, usize) {
+    (x as usize, y as usize, u as usize, v as usize)
+}
@@ -0,0 +1,28 @@
+//! Binary encoding of protocol messages, as a compact alternative to JSON.
+
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+/// Converts a protocol message from JSON to MessagePack, for sending to the
+/// server in a binary WebSocket frame.
+///
+/// # Error
+///
+/// Returns `None` if the input is not valid JSON.
+#[wasm_bindgen]
+pub fn encode_msgpack(json: &str) -> Option<Vec<u8>> {
+    let value: Value = serde_json::from_str(json).ok()?;
+    rmp_serde::to_vec_named(&value).ok()
+}
+
+/// Converts a protocol message received in a binary WebSocket frame from
+/// MessagePack to JSON.
+///
+/// # Error
+///
+/// Returns `None` if the input is not valid MessagePack.
+#[wasm_bindgen]
+pub fn decode_msgpack(bytes: &[u8]) -> Option<String> {
+    let value: Value = rmp_serde::from_slice(bytes).ok()?;
+    serde_json::to_string(&value).ok()
+}
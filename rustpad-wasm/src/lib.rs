@@ -6,6 +6,8 @@ use operational_transform::OperationSeq;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
+mod diff;
+pub mod encoding;
 pub mod utils;
 
 /// This is an wrapper around `operational_transform::OperationSeq`, which is
@@ -39,6 +41,18 @@ impl OpSeq {
     }
 }
 
+impl From<OperationSeq> for OpSeq {
+    fn from(operation: OperationSeq) -> Self {
+        Self(operation)
+    }
+}
+
+impl From<OpSeq> for OperationSeq {
+    fn from(operation: OpSeq) -> Self {
+        operation.0
+    }
+}
+
 #[wasm_bindgen]
 impl OpSeq {
     /// Creates a default empty `OpSeq`.
@@ -52,6 +66,22 @@ impl OpSeq {
         Self(OperationSeq::with_capacity(capacity))
     }
 
+    /// Creates an operation that replaces the text `current` with the `text` of
+    /// a snapshot sent by the server, for initializing a client that has no
+    /// history.
+    pub fn from_snapshot(current: &str, text: &str) -> Self {
+        let mut operation = OperationSeq::default();
+        operation.delete(bytecount::num_chars(current.as_bytes()) as u64);
+        operation.insert(text);
+        Self(operation)
+    }
+
+    /// Creates a minimal operation that turns the text `old` into `new`, so
+    /// that unchanged parts of the text keep their positions and cursors.
+    pub fn from_diff(old: &str, new: &str) -> Self {
+        Self(diff::diff(old, new))
+    }
+
     /// Merges the operation with `other` into one operation while preserving
     /// the changes of both. Or, in other words, for each input string S and a
     /// pair of consecutive operations A and B.
@@ -176,3 +206,221 @@ impl OpSeqPair {
         self.1.clone()
     }
 }
+
+/// The client side of the synchronization protocol, tracking local edits that
+/// the server has not acknowledged yet.
+///
+/// At most one edit is sent to the server at a time, as the outstanding edit.
+/// Local edits made while it awaits acknowledgement are composed into a buffer,
+/// which is sent once it is acknowledged. Edits from other users are
+/// transformed against both before being applied locally.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug)]
+pub struct ClientState {
+    revision: usize,
+    outstanding: Option<OpSeq>,
+    buffer: Option<OpSeq>,
+}
+
+/// The result of applying a `History` message with `ClientState`, which
+/// is needed to return multiple values from `wasm-bindgen`.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct HistoryUpdate(OpSeq, Option<OpSeq>);
+
+/// Edits by other users, each with the ID of its author.
+pub type UserEdits = Vec<(u64, OpSeq)>;
+
+/// A range of operations sent by the server in a `History` message.
+#[derive(Deserialize)]
+struct History {
+    start: usize,
+    operations: Vec<UserOperation>,
+}
+
+/// An operation in the history, along with the ID of its author.
+#[derive(Deserialize)]
+struct UserOperation {
+    id: u64,
+    operation: OpSeq,
+}
+
+#[wasm_bindgen]
+impl ClientState {
+    /// Creates the state of a client with no history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the state of a client whose text was initialized from a
+    /// snapshot at `revision`.
+    pub fn from_snapshot(revision: usize) -> Self {
+        Self {
+            revision,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the number of operations received from the server.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Returns the edit sent to the server and awaiting acknowledgement, which
+    /// must be sent again after reconnecting.
+    pub fn outstanding(&self) -> Option<OpSeq> {
+        self.outstanding.clone()
+    }
+
+    /// Checks if there are local edits waiting to be sent to the server.
+    pub fn has_buffer(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Records an edit made locally, returning it if it should be sent to the
+    /// server now, or `None` if it waits for the outstanding edit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the edit does not apply to the text after previous local
+    /// edits.
+    pub fn apply_local(&mut self, operation: &OpSeq) -> Option<OpSeq> {
+        if self.outstanding.is_none() {
+            self.outstanding = Some(operation.clone());
+            return self.outstanding.clone();
+        }
+        self.buffer = Some(match &self.buffer {
+            Some(buffer) => buffer
+                .compose(operation)
+                .expect("local edit does not follow buffered edits"),
+            None => operation.clone(),
+        });
+        None
+    }
+
+    /// Handles the acknowledgement of the outstanding edit, returning the
+    /// buffered edit to send next, if any.
+    pub fn server_ack(&mut self) -> Option<OpSeq> {
+        self.revision += 1;
+        self.outstanding = self.buffer.take();
+        self.outstanding.clone()
+    }
+
+    /// Handles an edit by another user, returning it transformed to apply to
+    /// the local text.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` if the edit conflicts with the length of local edits.
+    pub fn apply_server(&mut self, operation: &OpSeq) -> Option<OpSeq> {
+        let mut operation = operation.clone();
+        for local in [&mut self.outstanding, &mut self.buffer]
+            .into_iter()
+            .flatten()
+        {
+            let (local_prime, operation_prime) = local.transform_raw(&operation)?;
+            *local = local_prime;
+            operation = operation_prime;
+        }
+        self.revision += 1;
+        Some(operation)
+    }
+
+    /// Handles the body of a `History` message in JSON, skipping operations
+    /// that were already received. Operations by the user `me` acknowledge
+    /// the outstanding edit, and the others are transformed and composed into
+    /// one edit to apply to the local text.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` if the message is invalid, starts after the current
+    /// revision, or conflicts with local edits. The state is left unchanged,
+    /// and the client should reconnect.
+    pub fn apply_server_history(&mut self, me: u64, history: &str) -> Option<HistoryUpdate> {
+        let History { start, operations } = serde_json::from_str(history).ok()?;
+        let operations = operations
+            .into_iter()
+            .map(|UserOperation { id, operation }| (id, operation));
+        let mut state = self.clone();
+        let (remote, send) = state.apply_history_raw(me, start, operations)?;
+        let mut apply: Option<OpSeq> = None;
+        for (_, operation) in remote {
+            apply = Some(match apply {
+                Some(apply) => apply.compose(&operation)?,
+                None => operation,
+            });
+        }
+        *self = state;
+        Some(HistoryUpdate(apply.unwrap_or_default(), send))
+    }
+
+    /// Returns the local position of a remote user's cursor, given its
+    /// position in the text last received from the server.
+    pub fn transform_cursor(&self, position: u32) -> u32 {
+        [&self.outstanding, &self.buffer]
+            .into_iter()
+            .flatten()
+            .fold(position, |position, local| local.transform_index(position))
+    }
+}
+
+impl ClientState {
+    /// Handles a range of operations in the history starting at revision
+    /// `start`, along with the IDs of their authors, skipping operations that
+    /// were already received. Operations by the user `me` acknowledge the
+    /// outstanding edit.
+    ///
+    /// Unlike `ClientState::apply_server_history`, this function returns each
+    /// edit by another user separately, transformed to apply to the local text
+    /// in order, but cannot be exported by `wasm-bindgen`. The second element
+    /// is the buffered edit to send to the server if the outstanding edit was
+    /// acknowledged.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` if the operations start after the current revision or
+    /// conflict with local edits. The state is only updated if every operation
+    /// was handled.
+    pub fn apply_history_raw(
+        &mut self,
+        me: u64,
+        start: usize,
+        operations: impl IntoIterator<Item = (u64, OpSeq)>,
+    ) -> Option<(UserEdits, Option<OpSeq>)> {
+        if start > self.revision {
+            return None;
+        }
+        let skip = self.revision - start;
+        let mut state = self.clone();
+        let mut remote = Vec::new();
+        let mut acknowledged = false;
+        for (id, operation) in operations.into_iter().skip(skip) {
+            if id == me {
+                // An acknowledgement must have an outstanding edit.
+                state.outstanding.as_ref()?;
+                state.server_ack();
+                acknowledged = true;
+            } else {
+                remote.push((id, state.apply_server(&operation)?));
+            }
+        }
+        *self = state;
+        let send = self.outstanding.clone().filter(|_| acknowledged);
+        Some((remote, send))
+    }
+}
+
+#[wasm_bindgen]
+impl HistoryUpdate {
+    /// Returns the edit to apply to the local text, which has no effect if
+    /// the history only acknowledged local edits.
+    pub fn apply(&self) -> OpSeq {
+        self.0.clone()
+    }
+
+    /// Returns the buffered edit to send to the server after the history was
+    /// applied, if the outstanding edit was acknowledged.
+    pub fn send(&self) -> Option<OpSeq> {
+        self.1.clone()
+    }
+}
@@ -0,0 +1,116 @@
+//! Tests for the client synchronization state machine, run natively.
+
+use rustpad_wasm::{ClientState, OpSeq};
+use serde_json::json;
+
+fn insert(retain: u32, text: &str, rest: u32) -> OpSeq {
+    let mut o = OpSeq::default();
+    o.retain(retain);
+    o.insert(text);
+    o.retain(rest);
+    o
+}
+
+#[test]
+fn buffer_local_edits() {
+    let mut state = ClientState::new();
+    let a = insert(0, "abc", 0);
+    let b = insert(3, "def", 0);
+    let c = insert(6, "ghi", 0);
+    assert_eq!(state.apply_local(&a), Some(a.clone()));
+    assert_eq!(state.apply_local(&b), None);
+    assert_eq!(state.apply_local(&c), None);
+    assert!(state.has_buffer());
+
+    let bc = state.server_ack().unwrap();
+    assert_eq!(bc.apply("abc").unwrap(), "abcdefghi");
+    assert_eq!(state.outstanding(), Some(bc));
+    assert!(!state.has_buffer());
+    assert_eq!(state.server_ack(), None);
+    assert_eq!(state.revision(), 2);
+}
+
+#[test]
+fn concurrent_history() {
+    // The client types "abc" while another user types "xyz" in an empty
+    // document, and the server applies the other user's edit first.
+    let mut state = ClientState::new();
+    let local = insert(0, "abc", 0);
+    state.apply_local(&local).unwrap();
+    let buffered = insert(3, "!", 0);
+    assert_eq!(state.apply_local(&buffered), None);
+
+    let remote = insert(0, "xyz", 0);
+    let (local_prime, _) = local.transform_raw(&remote).unwrap();
+    let server_text = local_prime.apply(&remote.apply("").unwrap()).unwrap();
+    let history = json!({
+        "start": 0,
+        "operations": [
+            { "id": 1, "operation": remote },
+            { "id": 0, "operation": local_prime },
+        ]
+    });
+    let update = state.apply_server_history(0, &history.to_string()).unwrap();
+    assert_eq!(state.revision(), 2);
+
+    let client_text = update.apply().apply("abc!").unwrap();
+    let send = update.send().unwrap();
+    assert_eq!(send.apply(&server_text).unwrap(), client_text);
+    assert_eq!(state.outstanding(), Some(send));
+
+    // Replaying the same history has no effect.
+    let update = state.apply_server_history(0, &history.to_string()).unwrap();
+    assert!(update.apply().is_noop());
+    assert_eq!(update.send(), None);
+}
+
+#[test]
+fn invalid_history() {
+    let mut state = ClientState::new();
+    let history = json!({ "start": 1, "operations": [] });
+    assert!(state
+        .apply_server_history(0, &history.to_string())
+        .is_none());
+
+    let history = json!({
+        "start": 0,
+        "operations": [{ "id": 0, "operation": ["abc"] }]
+    });
+    assert!(state
+        .apply_server_history(0, &history.to_string())
+        .is_none());
+}
+
+#[test]
+fn transform_cursor() {
+    let mut state = ClientState::from_snapshot(5);
+    assert_eq!(state.transform_cursor(2), 2);
+    state.apply_local(&insert(0, "ab", 3));
+    state.apply_local(&insert(4, "cd", 1));
+    assert_eq!(state.transform_cursor(0), 2);
+    assert_eq!(state.transform_cursor(2), 6);
+    assert_eq!(state.transform_cursor(3), 7);
+}
+
+#[test]
+fn failed_history_is_not_applied() {
+    let mut state = ClientState::new();
+    let local = insert(0, "abc", 0);
+    state.apply_local(&local).unwrap();
+    state.apply_local(&insert(3, "!", 0));
+
+    // The acknowledgement is valid, but the next edit is too long.
+    let history = json!({
+        "start": 0,
+        "operations": [
+            { "id": 0, "operation": local },
+            { "id": 1, "operation": insert(10, "xyz", 0) },
+        ]
+    });
+    assert!(state
+        .apply_server_history(0, &history.to_string())
+        .is_none());
+    assert_eq!(state.revision(), 0);
+    assert_eq!(state.outstanding(), Some(local));
+    assert!(state.has_buffer());
+}
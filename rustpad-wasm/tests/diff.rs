@@ -0,0 +1,101 @@
+//! Tests for computing operations from text diffs, run natively.
+
+use rustpad_wasm::OpSeq;
+use serde_json::json;
+
+/// Returns the number of characters deleted and inserted by an operation.
+fn cost(o: &OpSeq) -> usize {
+    let value = serde_json::to_value(o).unwrap();
+    let mut cost = 0;
+    for op in value.as_array().unwrap() {
+        if let Some(n) = op.as_i64().filter(|&n| n < 0) {
+            cost += n.unsigned_abs() as usize;
+        } else if let Some(s) = op.as_str() {
+            cost += s.chars().count();
+        }
+    }
+    cost
+}
+
+/// Returns the length of the longest common subsequence of two strings.
+fn lcs(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row = vec![0; b.len() + 1];
+    for x in a.chars() {
+        let mut diagonal = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x == y {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[test]
+fn simple_edits() {
+    let o = OpSeq::from_diff("hello world", "hello, world!");
+    assert_eq!(serde_json::to_value(&o).unwrap(), json!([5, ",", 6, "!"]));
+
+    let o = OpSeq::from_diff("the quick brown fox", "the brown fox jumps");
+    assert_eq!(
+        serde_json::to_value(&o).unwrap(),
+        json!([4, -6, 9, " jumps"])
+    );
+
+    assert!(OpSeq::from_diff("", "").is_noop());
+    assert!(OpSeq::from_diff("same", "same").is_noop());
+    assert_eq!(OpSeq::from_diff("", "new").apply("").unwrap(), "new");
+    assert_eq!(OpSeq::from_diff("old", "").apply("old").unwrap(), "");
+}
+
+#[test]
+fn unicode() {
+    let o = OpSeq::from_diff("h\u{e9}llo \u{1f600}", "h\u{e8}llo \u{1f600}!");
+    assert_eq!(o.base_len(), 7);
+    assert_eq!(
+        serde_json::to_value(&o).unwrap(),
+        json!([1, "\u{e8}", -1, 5, "!"])
+    );
+}
+
+#[test]
+fn minimal() {
+    // Deterministic pseudo-random strings over a small alphabet, so that they
+    // share many subsequences.
+    let mut seed: u64 = 42;
+    let mut random = |n: u64| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (seed >> 33) % n
+    };
+    for _ in 0..500 {
+        let mut string = || -> String {
+            let len = random(30);
+            (0..len).map(|_| (b'a' + random(4) as u8) as char).collect()
+        };
+        let (a, b) = (string(), string());
+        let o = OpSeq::from_diff(&a, &b);
+        assert_eq!(o.apply(&a).unwrap(), b, "diff of {a:?} and {b:?}");
+        let expected = a.len() + b.len() - 2 * lcs(&a, &b);
+        assert_eq!(cost(&o), expected, "diff of {a:?} and {b:?}");
+    }
+}
+
+#[test]
+fn large_texts() {
+    let a: String = (0..20000).map(|i| format!("line {i}\n")).collect();
+    let b: String = (0..20000)
+        .map(|i| match i % 100 {
+            0 => format!("changed {i}\n"),
+            _ => format!("line {i}\n"),
+        })
+        .collect();
+    let o = OpSeq::from_diff(&a, &b);
+    assert_eq!(o.apply(&a).unwrap(), b);
+    // Each changed line keeps "ne" from "line" and inserts "chaged".
+    assert_eq!(cost(&o), 200 * ("line".len() + "changed".len() - 4));
+}
@@ -2,6 +2,7 @@
 
 #![cfg(target_arch = "wasm32")]
 
+use rustpad_wasm::encoding::{decode_msgpack, encode_msgpack};
 use rustpad_wasm::OpSeq;
 
 use wasm_bindgen_test::*;
@@ -63,3 +64,27 @@ fn transform_index() {
     assert_eq!(o.transform_index(5), 8);
     assert_eq!(o.transform_index(7), 13);
 }
+
+#[wasm_bindgen_test]
+fn from_snapshot() {
+    let o = OpSeq::from_snapshot("h\u{e9}llo", "world");
+    assert_eq!(o.base_len(), 5);
+    assert_eq!(o.apply("h\u{e9}llo").unwrap(), "world");
+    assert!(OpSeq::from_snapshot("", "").is_noop());
+}
+
+#[wasm_bindgen_test]
+fn from_diff() {
+    let o = OpSeq::from_diff("hello world", "hello, world!");
+    assert_eq!(o.to_string(), r#"[5,",",6,"!"]"#);
+    assert_eq!(o.apply("hello world").unwrap(), "hello, world!");
+}
+
+#[wasm_bindgen_test]
+fn msgpack_round_trip() {
+    let json = r#"{"Edit":{"operation":[3,"def",-2],"revision":4}}"#;
+    let bytes = encode_msgpack(json).unwrap();
+    assert!(bytes.len() < json.len());
+    assert_eq!(decode_msgpack(&bytes).unwrap(), json);
+    assert_eq!(encode_msgpack("{"), None);
+}
@@ -25,6 +25,7 @@ const version = import.meta.env?.VERSION || "dev";
 function getWsUri(id: string) {
   let url = new URL(`api/socket/${id}`, window.location.href);
   url.protocol = url.protocol == "https:" ? "wss:" : "ws:";
+  url.searchParams.set("encoding", "json-deflate");
   return url.href;
 }
 
@@ -90,6 +91,15 @@ function App() {
             duration: undefined,
           });
         },
+        onOutdated: () => {
+          setConnection("desynchronized");
+          toaster.create({
+            title: "Rustpad has been updated",
+            description: "Please save your work and reload the page.",
+            type: "error",
+            duration: undefined,
+          });
+        },
         onChangeLanguage: (language) => {
           if (languages.getLanguages().some((it) => it.id === language)) {
             setLanguage(language);
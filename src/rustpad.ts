@@ -1,6 +1,6 @@
 import debounce from "lodash.debounce";
 import type { IDisposable, IPosition, editor } from "monaco-editor";
-import { OpSeq, initSync } from "rustpad-wasm/rustpad_wasm";
+import { ClientState, OpSeq, initSync } from "rustpad-wasm/rustpad_wasm";
 
 // Bun cannot automatically bundle and init wasm modules, so we have to do it manually.
 // See: https://github.com/flowscripter/template-bun-wasm-rust-library
@@ -10,6 +10,12 @@ const wasmBuffer = typeof Bun !== "undefined"
   : await fetch(wasm as any).then((response) => response.arrayBuffer());
 
 
+/** Version of the WebSocket protocol spoken by this client. */
+const PROTOCOL_VERSION = 1;
+
+/** Close code sent by servers that do not speak `PROTOCOL_VERSION`. */
+const CLOSE_UNSUPPORTED_VERSION = 4000;
+
 /** Options passed in to the Rustpad constructor. */
 export type RustpadOptions = {
   readonly uri: string;
@@ -17,6 +23,7 @@ export type RustpadOptions = {
   readonly onConnected?: () => void;
   readonly onDisconnected?: () => void;
   readonly onDesynchronized?: () => void;
+  readonly onOutdated?: () => void;
   readonly onChangeLanguage?: (language: string) => void;
   readonly onChangeUsers?: (users: Record<number, UserInfo>) => void;
   readonly reconnectInterval?: number;
@@ -43,9 +50,7 @@ class Rustpad {
 
   // Client-server state
   private me: number = -1;
-  private revision: number = 0;
-  private outstanding?: OpSeq;
-  private buffer?: OpSeq;
+  private state: ClientState;
   private users: Record<number, UserInfo> = {};
   private userCursors: Record<number, CursorData> = {};
   private myInfo?: UserInfo;
@@ -59,6 +64,7 @@ class Rustpad {
   constructor(readonly options: RustpadOptions) {
     // Initialize the Rust WASM module. This must be done before any `OpSeq` methods are called.
     initSync(wasmBuffer);
+    this.state = ClientState.new();
 
     this.model = options.editor.getModel()!;
     this.onChangeHandle = options.editor.onDidChangeModelContent((e) =>
@@ -74,7 +80,7 @@ class Rustpad {
       cursorUpdate();
     });
     this.beforeUnload = (event: BeforeUnloadEvent) => {
-      if (this.outstanding) {
+      if (this.state.outstanding()) {
         event.preventDefault();
         event.returnValue = "";
       } else {
@@ -129,8 +135,14 @@ class Rustpad {
   private tryConnect() {
     if (this.connecting || this.ws) return;
     this.connecting = true;
-    console.info("connecting to", this.options.uri);
-    const ws = new WebSocket(this.options.uri);
+    const uri = new URL(this.options.uri);
+    uri.searchParams.set("version", String(PROTOCOL_VERSION));
+    if (this.state.revision() === 0 && !this.state.outstanding()) {
+      // Without local history, the current text can replace the full history.
+      uri.searchParams.set("snapshot", "true");
+    }
+    console.info("connecting to", uri.href);
+    const ws = new WebSocket(uri);
     ws.onopen = () => {
       console.info("connected to", this.options.uri);
       this.connecting = false;
@@ -140,12 +152,21 @@ class Rustpad {
       this.options.onChangeUsers?.(this.users);
       this.sendInfo();
       this.sendCursorData();
-      if (this.outstanding) {
-        this.sendOperation(this.outstanding);
+      const outstanding = this.state.outstanding();
+      if (outstanding) {
+        this.sendOperation(outstanding);
       }
     };
-    ws.onclose = () => {
-      if (this.ws) {
+    ws.onclose = ({ code }) => {
+      if (code === CLOSE_UNSUPPORTED_VERSION) {
+        // Reconnecting cannot help until the page is reloaded with a client
+        // that speaks the same protocol version as the server.
+        console.warn("server does not speak protocol version", PROTOCOL_VERSION);
+        this.ws = undefined;
+        this.connecting = false;
+        this.dispose();
+        this.options.onOutdated?.();
+      } else if (this.ws) {
         console.warn("disconnected from", this.options.uri);
         this.ws = undefined;
         this.options.onDisconnected?.();
@@ -159,32 +180,58 @@ class Rustpad {
         this.connecting = false;
       }
     };
+    ws.binaryType = "arraybuffer";
+    // Compressed messages are inflated asynchronously, so all messages are
+    // handled through a promise chain to keep them in order. The chain belongs
+    // to this socket, and messages still in it after the socket is replaced
+    // are dropped rather than applied to the state of the next connection.
+    let received = Promise.resolve();
     ws.onmessage = ({ data }) => {
-      if (typeof data === "string") {
-        this.handleMessage(JSON.parse(data));
-      }
+      received = received
+        .then(async () => {
+          const text = typeof data === "string" ? data : await inflate(data);
+          if (this.ws === ws) {
+            this.handleMessage(JSON.parse(text));
+          }
+        })
+        .catch((error) => {
+          // Reconnecting sends the history again from the last revision that
+          // was applied.
+          console.warn("Failed to handle message, resynchronizing:", error);
+          if (this.ws === ws) {
+            ws.close();
+          }
+        });
     };
   }
 
   private handleMessage(msg: ServerMsg) {
-    if (msg.Identity !== undefined) {
+    if (msg.Hello !== undefined) {
+      console.info("protocol version", msg.Hello.version, msg.Hello.features);
+    } else if (msg.Identity !== undefined) {
       this.me = msg.Identity;
+    } else if (msg.Snapshot !== undefined) {
+      const { revision, text } = msg.Snapshot;
+      if (this.state.revision() !== 0 || this.state.outstanding()) {
+        console.warn("Snapshot message received after local history.");
+        this.ws?.close();
+        return;
+      }
+      const current = this.model.getValue();
+      this.applyOperation(OpSeq.from_snapshot(current, text));
+      this.state = ClientState.from_snapshot(revision);
     } else if (msg.History !== undefined) {
-      const { start, operations } = msg.History;
-      if (start > this.revision) {
-        console.warn("History message has start greater than last operation.");
+      const history = JSON.stringify(msg.History);
+      const update = this.state.apply_server_history(this.me, history);
+      if (!update) {
+        console.warn("History message is inconsistent with local state.");
         this.ws?.close();
         return;
       }
-      for (let i = this.revision - start; i < operations.length; i++) {
-        let { id, operation } = operations[i]!;
-        this.revision++;
-        if (id === this.me) {
-          this.serverAck();
-        } else {
-          operation = OpSeq.from_str(JSON.stringify(operation));
-          this.applyServer(operation);
-        }
+      this.applyOperation(update.apply());
+      const operation = update.send();
+      if (operation) {
+        this.sendOperation(operation);
       }
     } else if (msg.Language !== undefined) {
       this.options.onChangeLanguage?.(msg.Language);
@@ -204,53 +251,34 @@ class Rustpad {
     } else if (msg.UserCursor !== undefined) {
       const { id, data } = msg.UserCursor;
       if (id !== this.me) {
-        this.userCursors[id] = data;
+        // Cursors are relative to the server's text, which does not include
+        // local edits that it has not acknowledged yet.
+        const transform = (c: number) => this.state.transform_cursor(c);
+        this.userCursors[id] = {
+          cursors: data.cursors.map(transform),
+          selections: data.selections.map(([s, e]) => [
+            transform(s),
+            transform(e),
+          ]),
+        };
         this.updateCursors();
       }
+    } else if (msg.Error !== undefined) {
+      console.warn("Server rejected message:", msg.Error);
     }
   }
 
-  private serverAck() {
-    if (!this.outstanding) {
-      console.warn("Received serverAck with no outstanding operation.");
-      return;
-    }
-    this.outstanding = this.buffer;
-    this.buffer = undefined;
-    if (this.outstanding) {
-      this.sendOperation(this.outstanding);
-    }
-  }
-
-  private applyServer(operation: OpSeq) {
-    if (this.outstanding) {
-      const pair = this.outstanding.transform(operation)!;
-      this.outstanding = pair.first();
-      operation = pair.second();
-      if (this.buffer) {
-        const pair = this.buffer.transform(operation)!;
-        this.buffer = pair.first();
-        operation = pair.second();
-      }
-    }
-    this.applyOperation(operation);
-  }
-
   private applyClient(operation: OpSeq) {
-    if (!this.outstanding) {
-      this.sendOperation(operation);
-      this.outstanding = operation;
-    } else if (!this.buffer) {
-      this.buffer = operation;
-    } else {
-      this.buffer = this.buffer.compose(operation);
+    const send = this.state.apply_local(operation);
+    if (send) {
+      this.sendOperation(send);
     }
     this.transformCursors(operation);
   }
 
   private sendOperation(operation: OpSeq) {
     const op = operation.to_string();
-    this.ws?.send(`{"Edit":{"revision":${this.revision},"operation":${op}}}`);
+    this.ws?.send(`{"Edit":{"revision":${this.state.revision()},"operation":${op}}}`);
   }
 
   private sendInfo() {
@@ -260,7 +288,7 @@ class Rustpad {
   }
 
   private sendCursorData() {
-    if (!this.buffer) {
+    if (!this.state.has_buffer()) {
       this.ws?.send(`{"CursorData":${JSON.stringify(this.cursorData)}}`);
     }
   }
@@ -448,7 +476,15 @@ type CursorData = {
 };
 
 type ServerMsg = {
+  Hello?: {
+    version: number;
+    features: string[];
+  };
   Identity?: number;
+  Snapshot?: {
+    revision: number;
+    text: string;
+  };
   History?: {
     start: number;
     operations: UserOperation[];
@@ -462,8 +498,17 @@ type ServerMsg = {
     id: number;
     data: CursorData;
   };
+  Error?: string;
 };
 
+/** Decompresses a zlib-compressed message from the server. */
+async function inflate(data: ArrayBuffer): Promise<string> {
+  const stream = new Blob([data])
+    .stream()
+    .pipeThrough(new DecompressionStream("deflate"));
+  return await new Response(stream).text();
+}
+
 /** Returns the number of Unicode codepoints in a string. */
 function unicodeLength(str: string): number {
   let length = 0;